@@ -0,0 +1,207 @@
+use image::{self, Rgb};
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The size of the thumbnail which is analyzed instead of the whole image
+const ANALYSIS_SIZE: u32 = 64;
+
+/// The amount of ranges the hues of the pixels are sorted into, each 30° wide
+const HUE_RANGES: usize = 12;
+
+/// Pixels with a lower saturation are gray and have no hue
+const MIN_SATURATION: f32 = 0.2;
+
+/// Pixels with a lower value are black and have no hue
+const MIN_VALUE: f32 = 0.15;
+
+/// The share of an image a hue range has to cover to be dominant
+const MIN_SHARE: f32 = 0.1;
+
+/// The maximum amount of dominant hues which are remembered
+const MAX_DOMINANT: usize = 3;
+
+/// How many degrees a dominant hue may be off the preferred hue
+const HUE_TOLERANCE: f32 = 30.0;
+
+/// The names of hues which can be used instead of degrees
+const HUE_NAMES: [(&str, f32); 9] = [
+    ("red", 0.0),
+    ("orange", 30.0),
+    ("yellow", 60.0),
+    ("green", 120.0),
+    ("cyan", 180.0),
+    ("blue", 240.0),
+    ("purple", 270.0),
+    ("magenta", 300.0),
+    ("pink", 330.0),
+];
+
+/// The brightness, contrast and dominant colors of an image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Colors {
+    /// the mean luminance, from 0 (black) to 1 (white)
+    pub brightness: f32,
+    /// the standard deviation of the luminance, from 0 (a single color) to 0.5
+    pub contrast: f32,
+    /// the hues in degrees which cover large parts of the image, the largest first
+    pub dominant: Vec<f32>,
+}
+
+impl Colors {
+    /// Analyzes the colors of the image [file]
+    pub fn analyze(file: &Path) -> Result<Self, String> {
+        let image =
+            image::open(file).map_err(|error| format!("could not decode image: {}", error))?;
+        let small = image.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_rgb8();
+        let count = small.pixels().len() as f32;
+
+        let mut luminances = Vec::with_capacity(small.pixels().len());
+        let mut hues = [0u32; HUE_RANGES];
+        for pixel in small.pixels() {
+            luminances.push(luminance(pixel));
+            let [red, green, blue] = pixel.0.map(|channel| f32::from(channel) / 255.0);
+            if let Some(hue) = hue(red, green, blue) {
+                // the ranges are centered on multiples of 30°, so that red is not split up
+                let width = 360.0 / HUE_RANGES as f32;
+                hues[((hue + width / 2.0) / width) as usize % HUE_RANGES] += 1;
+            }
+        }
+
+        let brightness = luminances.iter().sum::<f32>() / count;
+        let variance = luminances
+            .iter()
+            .map(|luminance| (luminance - brightness).powi(2))
+            .sum::<f32>()
+            / count;
+
+        let mut ranges: Vec<usize> = (0..HUE_RANGES)
+            .filter(|&range| hues[range] as f32 / count >= MIN_SHARE)
+            .collect();
+        ranges.sort_by_key(|&range| Reverse(hues[range]));
+        let dominant = ranges
+            .into_iter()
+            .take(MAX_DOMINANT)
+            .map(|range| range as f32 * 360.0 / HUE_RANGES as f32)
+            .collect();
+
+        Ok(Colors {
+            brightness,
+            contrast: variance.sqrt(),
+            dominant,
+        })
+    }
+
+    /// 'true' if one of the dominant hues is close to [hue]
+    pub fn has_hue(&self, hue: &Hue) -> bool {
+        self.dominant.iter().any(|&dominant| {
+            let difference = (dominant - hue.degrees).abs() % 360.0;
+            difference.min(360.0 - difference) <= HUE_TOLERANCE
+        })
+    }
+}
+
+impl Display for Colors {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let dominant: Vec<String> = self.dominant.iter().map(|hue| format!("{}°", hue)).collect();
+        write!(
+            f,
+            "brightness {:.2}, contrast {:.2}, dominant hues [{}]",
+            self.brightness,
+            self.contrast,
+            dominant.join(", ")
+        )
+    }
+}
+
+/// The relative luminance of [pixel], from 0 (black) to 1 (white)
+pub fn luminance(pixel: &Rgb<u8>) -> f32 {
+    let [red, green, blue] = pixel.0.map(|channel| f32::from(channel) / 255.0);
+    0.2126 * red + 0.7152 * green + 0.0722 * blue
+}
+
+/// The hue in degrees of the color with the components [red], [green] and [blue],
+/// or [None] if it is too dark or unsaturated to have a visible hue
+fn hue(red: f32, green: f32, blue: f32) -> Option<f32> {
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
+    let chroma = max - min;
+    if max < MIN_VALUE || chroma / max < MIN_SATURATION {
+        return None;
+    }
+
+    let sector = if max == red {
+        (green - blue) / chroma
+    } else if max == green {
+        (blue - red) / chroma + 2.0
+    } else {
+        (red - green) / chroma + 4.0
+    };
+    Some((sector * 60.0 + 360.0) % 360.0)
+}
+
+/// A hue like "blue" or "210", which dominant colors of wallpapers are compared to
+#[derive(Debug, Clone)]
+pub struct Hue {
+    source: String,
+    degrees: f32,
+}
+
+impl FromStr for Hue {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let name = source.trim().to_lowercase();
+        let degrees = HUE_NAMES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|&(_, degrees)| degrees)
+            .or_else(|| name.trim_end_matches('°').parse::<f32>().ok())
+            .filter(|degrees| (0.0..=360.0).contains(degrees))
+            .ok_or_else(|| {
+                let names: Vec<&str> = HUE_NAMES.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "Invalid hue '{}', use degrees from 0 to 360 or one of {}",
+                    source,
+                    names.join(", ")
+                )
+            })?;
+
+        Ok(Hue {
+            source: source.to_owned(),
+            degrees,
+        })
+    }
+}
+
+impl Display for Hue {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Serialize for Hue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Hue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Source {
+            Name(String),
+            Degrees(f32),
+        }
+
+        let source = match Source::deserialize(deserializer)? {
+            Source::Name(name) => name,
+            Source::Degrees(degrees) => degrees.to_string(),
+        };
+        source.parse().map_err(D::Error::custom)
+    }
+}
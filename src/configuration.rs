@@ -1,23 +1,90 @@
+use chrono::{DateTime, Duration, Local};
 use clap::ArgMatches;
+use colors::Hue;
+use derived::{Animated, Dimming};
 use meval::eval_str as str_to_i64;
+use pattern::Pattern;
+use platform::DISPLAY_FORMATS;
 use reddit::Mode;
-use std::path::Path;
+use rules::Rule;
+use schedule::Schedule;
+use selection::Weighting;
+use solar::SolarEvent;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use utils::{format_duration, parse_duration};
 
 pub const CONFIG_FILE_NAME: &'static str = "config.json";
 pub const RUN_BY_DEFAULT: &'static str = ".run-on-default";
 pub const INSTALL_DIR: &'static str = ".heaven-on-earth";
+pub const PROFILE_ENV_VAR: &str = "HEAVEN_ON_EARTH_PROFILE";
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Settings {
     pub mode: Option<Mode>,
     pub min_ratio: Option<f32>,
     pub max_ratio: Option<f32>,
     pub query_size: Option<u8>,
+    pub parallel_downloads: Option<u8>,
     pub run_every: Option<String>,
     pub output_dir: Option<String>,
     pub random: Option<bool>,
+    pub weighted_by: Option<Weighting>,
+    pub temperature: Option<f32>,
+    pub seed: Option<u64>,
     pub subreddits: Option<Vec<String>>,
     pub min_res: Option<f32>,
+    pub max_size: Option<f32>,
+    pub display_formats: Option<Vec<String>>,
+    pub convert_to: Option<String>,
+    pub animated: Option<Animated>,
+    pub allow_nsfw: Option<bool>,
+    pub allow_spoilers: Option<bool>,
+    pub allow_stickied: Option<bool>,
+    pub min_score: Option<i64>,
+    pub min_upvote_ratio: Option<f32>,
+    pub min_comments: Option<u32>,
+    pub max_age: Option<String>,
+    pub min_age: Option<String>,
+    pub include_titles: Option<Vec<String>>,
+    pub exclude_titles: Option<Vec<String>>,
+    pub prefer_titles: Option<Vec<String>>,
+    pub match_flair: Option<bool>,
+    pub allowed_authors: Option<Vec<String>>,
+    pub blocked_authors: Option<Vec<String>>,
+    pub allowed_domains: Option<Vec<String>>,
+    pub blocked_domains: Option<Vec<String>>,
+    pub duplicate_threshold: Option<u32>,
+    pub min_brightness: Option<f32>,
+    pub max_brightness: Option<f32>,
+    pub max_contrast: Option<f32>,
+    pub prefer_hue: Option<Hue>,
+    pub target_brightness: Option<f32>,
+    pub dimming: Option<Dimming>,
+    pub rules: Option<Vec<Rule>>,
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+    pub run_at: Option<Vec<SolarEvent>>,
+    pub jitter: Option<String>,
+    pub reddit_url: Option<String>,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<String>,
+    pub request_interval: Option<String>,
+    pub user_agent: Option<String>,
+    pub connect_timeout: Option<String>,
+    pub read_timeout: Option<String>,
+    pub proxy: Option<String>,
+    pub ca_certificate: Option<String>,
+}
+
+/// The content of the configuration file: shared settings
+/// and named profiles which are layered on top of them
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ConfigFile {
+    #[serde(flatten)]
+    pub settings: Settings,
+    #[serde(default)]
+    pub profiles: HashMap<String, Settings>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -26,11 +93,66 @@ pub struct Configuration {
     pub min_ratio: Option<f32>,
     pub max_ratio: Option<f32>,
     pub query_size: u8,
-    pub run_every: Option<String>,
+    pub parallel_downloads: u8,
+    pub run_every: Option<Schedule>,
     pub output_dir: String,
     pub random: bool,
+    pub weighted_by: Option<Weighting>,
+    pub temperature: f32,
+    pub seed: Option<u64>,
     pub subreddits: Vec<String>,
     pub min_res: Option<f32>,
+    pub max_size: f32,
+    pub display_formats: Vec<String>,
+    pub convert_to: String,
+    pub animated: Animated,
+    pub allow_nsfw: bool,
+    pub allow_spoilers: bool,
+    pub allow_stickied: bool,
+    pub min_score: Option<i64>,
+    pub min_upvote_ratio: Option<f32>,
+    pub min_comments: Option<u32>,
+    #[serde(with = "::utils::duration_string::option")]
+    pub max_age: Option<Duration>,
+    #[serde(with = "::utils::duration_string::option")]
+    pub min_age: Option<Duration>,
+    pub include_titles: Vec<Pattern>,
+    pub exclude_titles: Vec<Pattern>,
+    pub prefer_titles: Vec<Pattern>,
+    pub match_flair: bool,
+    pub allowed_authors: Vec<String>,
+    pub blocked_authors: Vec<String>,
+    pub allowed_domains: Vec<String>,
+    pub blocked_domains: Vec<String>,
+    pub duplicate_threshold: u32,
+    pub min_brightness: Option<f32>,
+    pub max_brightness: Option<f32>,
+    pub max_contrast: Option<f32>,
+    pub prefer_hue: Option<Hue>,
+    pub target_brightness: Option<f32>,
+    pub dimming: Dimming,
+    pub rules: Vec<Rule>,
+    pub latitude: Option<f32>,
+    pub longitude: Option<f32>,
+    pub run_at: Vec<SolarEvent>,
+    #[serde(with = "::utils::duration_string::option")]
+    pub jitter: Option<Duration>,
+    pub reddit_url: String,
+    pub retries: u32,
+    #[serde(with = "::utils::duration_string")]
+    pub retry_backoff: Duration,
+    #[serde(with = "::utils::duration_string")]
+    pub request_interval: Duration,
+    pub user_agent: String,
+    #[serde(with = "::utils::duration_string")]
+    pub connect_timeout: Duration,
+    #[serde(with = "::utils::duration_string")]
+    pub read_timeout: Duration,
+    pub proxy: Option<String>,
+    pub ca_certificate: Option<String>,
+    /// the settings given as command-line arguments, which take precedence over rules
+    #[serde(skip)]
+    pub command_line: Option<Settings>,
 }
 
 impl Default for Settings {
@@ -40,11 +162,60 @@ impl Default for Settings {
             min_ratio: None,
             max_ratio: None,
             query_size: Some(50),
+            parallel_downloads: Some(1),
             run_every: None,
             output_dir: Some("image-out".to_string()),
             random: Some(false),
+            weighted_by: None,
+            temperature: Some(1.0),
+            seed: None,
             subreddits: Some(vec!["EarthPorn".to_string(), "art".to_string()]),
             min_res: None,
+            max_size: Some(30.0),
+            display_formats: Some(DISPLAY_FORMATS.iter().map(|f| f.to_string()).collect()),
+            convert_to: Some("jpeg".to_string()),
+            animated: Some(Animated::Still),
+            allow_nsfw: Some(false),
+            allow_spoilers: Some(true),
+            allow_stickied: Some(false),
+            min_score: None,
+            min_upvote_ratio: None,
+            min_comments: None,
+            max_age: None,
+            min_age: None,
+            include_titles: None,
+            exclude_titles: None,
+            prefer_titles: None,
+            match_flair: Some(false),
+            allowed_authors: None,
+            blocked_authors: None,
+            allowed_domains: None,
+            blocked_domains: None,
+            duplicate_threshold: Some(6),
+            min_brightness: None,
+            max_brightness: None,
+            max_contrast: None,
+            prefer_hue: None,
+            target_brightness: None,
+            dimming: Some(Dimming::Overlay),
+            rules: None,
+            latitude: None,
+            longitude: None,
+            run_at: None,
+            jitter: None,
+            reddit_url: Some("https://www.reddit.com".to_string()),
+            retries: Some(3),
+            retry_backoff: Some("1s".to_string()),
+            request_interval: Some("1s".to_string()),
+            user_agent: Some(format!(
+                "{}:heaven-on-earth:{} (by /u/nyxcode)",
+                ::std::env::consts::OS,
+                env!("CARGO_PKG_VERSION")
+            )),
+            connect_timeout: Some("10s".to_string()),
+            read_timeout: Some("30s".to_string()),
+            proxy: None,
+            ca_certificate: None,
         }
     }
 }
@@ -58,44 +229,156 @@ impl Settings {
                 .map_err(|e| warn!("could not parse mode: {}", e))
                 .ok()
         });
-        let min_ratio = matches
-            .value_of("min-ratio")
-            .map(|i| str_to_i64(i).expect("could not parse min_ratio") as f32);
-        let max_ratio = matches
-            .value_of("max-ratio")
-            .map(|i| str_to_i64(i).expect("could not parse max_ratio") as f32);
-        let query_size = matches
-            .value_of("query-size")
-            .map(|i| str_to_i64(i).expect("could not parse query_size") as u8);
-        let min_res = matches
-            .value_of("min-res")
-            .map(|i| str_to_i64(i).expect("could not parse min_res") as f32);
+        let number = |name: &str| match matches.value_of(name) {
+            Some(value) => str_to_i64(value)
+                .map(Some)
+                .map_err(|error| format!("Invalid {} '{}': {}", name, value, error)),
+            None => Ok(None),
+        };
+        let min_ratio = number("min-ratio")?.map(|number| number as f32);
+        let max_ratio = number("max-ratio")?.map(|number| number as f32);
+        let query_size = number("query-size")?.map(|number| number as u8);
+        let parallel_downloads = number("parallel-downloads")?.map(|number| number as u8);
+        let min_res = number("min-res")?.map(|number| number as f32);
+        let max_size = number("max-size")?.map(|number| number as f32);
+        let min_score = number("min-score")?.map(|number| number as i64);
+        let min_upvote_ratio = number("min-upvote-ratio")?.map(|number| number as f32);
+        let min_comments = number("min-comments")?.map(|number| number as u32);
+        let min_brightness = number("min-brightness")?.map(|number| number as f32);
+        let max_brightness = number("max-brightness")?.map(|number| number as f32);
+        let max_contrast = number("max-contrast")?.map(|number| number as f32);
+        let target_brightness = number("target-brightness")?.map(|number| number as f32);
+        let dimming = match matches.value_of("dimming") {
+            Some(id) => Some(
+                Dimming::from_identifier(id)
+                    .ok_or_else(|| format!("Unsupported dimming '{}'", id))?,
+            ),
+            None => None,
+        };
+        let prefer_hue = match matches.value_of("prefer-hue") {
+            Some(hue) => Some(hue.parse::<Hue>()?),
+            None => None,
+        };
+        let max_age = matches.value_of("max-age").map(|age| age.to_owned());
+        let min_age = matches.value_of("min-age").map(|age| age.to_owned());
+        let patterns = |name: &str| {
+            matches
+                .values_of(name)
+                .map(|v| v.map(|pattern| pattern.to_string()).collect())
+        };
+        let include_titles = patterns("include-titles");
+        let exclude_titles = patterns("exclude-titles");
+        let prefer_titles = patterns("prefer-titles");
+        let latitude = number("latitude")?.map(|number| number as f32);
+        let longitude = number("longitude")?.map(|number| number as f32);
         let run_every = matches.value_of("run-every").map(|expr| expr.to_owned());
+        let run_at = match matches.values_of("run-at") {
+            Some(events) => Some(
+                events
+                    .map(|id| {
+                        SolarEvent::from_identifier(id)
+                            .ok_or_else(|| format!("Unsupported solar event '{}'", id))
+                    }).collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        let jitter = matches.value_of("jitter").map(|jitter| jitter.to_owned());
+        let weighted_by = match matches.value_of("weighted-by") {
+            Some(id) => Some(
+                Weighting::from_identifier(id)
+                    .ok_or_else(|| format!("Unsupported weighting '{}'", id))?,
+            ),
+            None => None,
+        };
+        let temperature = number("temperature")?.map(|number| number as f32);
+        let seed = match matches.value_of("seed") {
+            Some(seed) => Some(
+                seed.parse::<u64>()
+                    .map_err(|_| format!("Invalid seed '{}', use a non-negative integer", seed))?,
+            ),
+            None => None,
+        };
+        let animated = match matches.value_of("animated") {
+            Some(id) => Some(
+                Animated::from_identifier(id)
+                    .ok_or_else(|| format!("Unsupported handling of animations '{}'", id))?,
+            ),
+            None => None,
+        };
         let output_dir = matches.value_of("output-dir").map(|dir| dir.to_owned());
         let subreddits = matches
             .values_of("subreddits")
             .map(|v| v.map(|sub| sub.to_string()).collect());
-        let random: Option<bool> = matches
-            .value_of("random")
-            .map(|random| random.to_lowercase() == "true")
-            .or_else(|| {
-                if matches.is_present("random") {
-                    Some(true)
-                } else {
-                    None
-                }
-            });
+        let flag = |name: &str| -> Option<bool> {
+            matches
+                .value_of(name)
+                .map(|value| value.to_lowercase() == "true")
+                .or_else(|| {
+                    if matches.is_present(name) {
+                        Some(true)
+                    } else {
+                        None
+                    }
+                })
+        };
+        let random = flag("random");
+        let allow_nsfw = flag("allow-nsfw");
 
         let settings = Settings {
             mode,
             min_ratio,
             max_ratio,
             query_size,
+            parallel_downloads,
             run_every,
             output_dir,
             random,
+            weighted_by,
+            temperature,
+            seed,
             subreddits,
             min_res,
+            max_size,
+            display_formats: None,
+            convert_to: None,
+            animated,
+            allow_nsfw,
+            allow_spoilers: None,
+            allow_stickied: None,
+            min_score,
+            min_upvote_ratio,
+            min_comments,
+            max_age,
+            min_age,
+            include_titles,
+            exclude_titles,
+            prefer_titles,
+            match_flair: flag("match-flair"),
+            allowed_authors: None,
+            blocked_authors: None,
+            allowed_domains: None,
+            blocked_domains: None,
+            duplicate_threshold: None,
+            min_brightness,
+            max_brightness,
+            max_contrast,
+            prefer_hue,
+            target_brightness,
+            dimming,
+            rules: None,
+            latitude,
+            longitude,
+            run_at,
+            jitter,
+            reddit_url: None,
+            retries: None,
+            retry_backoff: None,
+            request_interval: None,
+            user_agent: None,
+            connect_timeout: None,
+            read_timeout: None,
+            proxy: None,
+            ca_certificate: None,
         };
 
         Ok(settings)
@@ -114,11 +397,56 @@ impl Settings {
             min_ratio: get(&settings, |setting| setting.min_ratio),
             max_ratio: get(&settings, |setting| setting.max_ratio),
             query_size: get(&settings, |setting| setting.query_size),
+            parallel_downloads: get(&settings, |setting| setting.parallel_downloads),
             run_every: get(&settings, |setting| setting.run_every.clone()),
             output_dir: get(&settings, |setting| setting.output_dir.clone()),
             random: get(&settings, |setting| setting.random),
+            weighted_by: get(&settings, |setting| setting.weighted_by),
+            temperature: get(&settings, |setting| setting.temperature),
+            seed: get(&settings, |setting| setting.seed),
             subreddits: get(&settings, |setting| setting.subreddits.clone()),
             min_res: get(&settings, |setting| setting.min_res.clone()),
+            max_size: get(&settings, |setting| setting.max_size),
+            display_formats: get(&settings, |setting| setting.display_formats.clone()),
+            convert_to: get(&settings, |setting| setting.convert_to.clone()),
+            animated: get(&settings, |setting| setting.animated),
+            allow_nsfw: get(&settings, |setting| setting.allow_nsfw),
+            allow_spoilers: get(&settings, |setting| setting.allow_spoilers),
+            allow_stickied: get(&settings, |setting| setting.allow_stickied),
+            min_score: get(&settings, |setting| setting.min_score),
+            min_upvote_ratio: get(&settings, |setting| setting.min_upvote_ratio),
+            min_comments: get(&settings, |setting| setting.min_comments),
+            max_age: get(&settings, |setting| setting.max_age.clone()),
+            min_age: get(&settings, |setting| setting.min_age.clone()),
+            include_titles: get(&settings, |setting| setting.include_titles.clone()),
+            exclude_titles: get(&settings, |setting| setting.exclude_titles.clone()),
+            prefer_titles: get(&settings, |setting| setting.prefer_titles.clone()),
+            match_flair: get(&settings, |setting| setting.match_flair),
+            allowed_authors: get(&settings, |setting| setting.allowed_authors.clone()),
+            blocked_authors: get(&settings, |setting| setting.blocked_authors.clone()),
+            allowed_domains: get(&settings, |setting| setting.allowed_domains.clone()),
+            blocked_domains: get(&settings, |setting| setting.blocked_domains.clone()),
+            duplicate_threshold: get(&settings, |setting| setting.duplicate_threshold),
+            min_brightness: get(&settings, |setting| setting.min_brightness),
+            max_brightness: get(&settings, |setting| setting.max_brightness),
+            max_contrast: get(&settings, |setting| setting.max_contrast),
+            prefer_hue: get(&settings, |setting| setting.prefer_hue.clone()),
+            target_brightness: get(&settings, |setting| setting.target_brightness),
+            dimming: get(&settings, |setting| setting.dimming),
+            rules: get(&settings, |setting| setting.rules.clone()),
+            latitude: get(&settings, |setting| setting.latitude),
+            longitude: get(&settings, |setting| setting.longitude),
+            run_at: get(&settings, |setting| setting.run_at.clone()),
+            jitter: get(&settings, |setting| setting.jitter.clone()),
+            reddit_url: get(&settings, |setting| setting.reddit_url.clone()),
+            retries: get(&settings, |setting| setting.retries),
+            retry_backoff: get(&settings, |setting| setting.retry_backoff.clone()),
+            request_interval: get(&settings, |setting| setting.request_interval.clone()),
+            user_agent: get(&settings, |setting| setting.user_agent.clone()),
+            connect_timeout: get(&settings, |setting| setting.connect_timeout.clone()),
+            read_timeout: get(&settings, |setting| setting.read_timeout.clone()),
+            proxy: get(&settings, |setting| setting.proxy.clone()),
+            ca_certificate: get(&settings, |setting| setting.ca_certificate.clone()),
         })
     }
 
@@ -127,47 +455,461 @@ impl Settings {
             option.ok_or_else(|| format!("Required setting '{}' missing", name))
         }
 
+        let location = match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => {
+                if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+                    return Err(format!("Invalid location {}, {}", latitude, longitude));
+                }
+                Some((latitude, longitude))
+            }
+            (None, None) => None,
+            _ => return Err("Both 'latitude' and 'longitude' are required".to_owned()),
+        };
+
+        let run_every = match self.run_every {
+            Some(expression) => Some(expression.parse::<Schedule>()?),
+            None => None,
+        };
+
+        let run_at = self.run_at.unwrap_or_default();
+        if !run_at.is_empty() && location.is_none() {
+            return Err("'run_at' requires 'latitude' and 'longitude'".to_owned());
+        }
+
+        fn duration(string: Option<String>) -> Result<Option<Duration>, String> {
+            string.map(|string| parse_duration(&string)).transpose()
+        }
+
+        let jitter = duration(self.jitter)?;
+        let max_age = duration(self.max_age)?;
+        let min_age = duration(self.min_age)?;
+        let retry_backoff = get(duration(self.retry_backoff)?, "retry_backoff")?;
+        let request_interval = get(duration(self.request_interval)?, "request_interval")?;
+        let convert_to = get(self.convert_to, "convert_to")?;
+        if convert_to != "jpeg" && convert_to != "png" {
+            return Err(format!("Cannot convert to '{}', use 'jpeg' or 'png'", convert_to));
+        }
+        let shares = [
+            ("min_brightness", self.min_brightness),
+            ("max_brightness", self.max_brightness),
+            ("max_contrast", self.max_contrast),
+            ("target_brightness", self.target_brightness),
+        ];
+        for (name, share) in shares.iter() {
+            if let Some(share) = share.filter(|share| !(0.0..=1.0).contains(share)) {
+                return Err(format!("Invalid {} {}, use a value from 0 to 1", name, share));
+            }
+        }
+        let temperature = get(self.temperature, "temperature")?;
+        if temperature.is_nan() || temperature <= 0.0 {
+            return Err(format!("Invalid temperature {}, use a value above 0", temperature));
+        }
+        let connect_timeout = get(duration(self.connect_timeout)?, "connect_timeout")?;
+        let read_timeout = get(duration(self.read_timeout)?, "read_timeout")?;
+        if let Some(ref proxy) = self.proxy {
+            ::http::check_proxy(proxy)?;
+        }
+
+        fn patterns(sources: Option<Vec<String>>) -> Result<Vec<Pattern>, String> {
+            sources
+                .unwrap_or_default()
+                .iter()
+                .map(|source| source.parse())
+                .collect()
+        }
+
+        let rules = self.rules.unwrap_or_default();
+        for rule in rules.iter() {
+            rule.validate(location)?;
+        }
+
         Ok(Configuration {
             mode: get(self.mode, "mode")?,
             min_ratio: get(self.min_ratio, "min_ratio").ok(),
             max_ratio: get(self.max_ratio, "max_ratio").ok(),
             query_size: get(self.query_size, "query_size")?,
-            run_every: self.run_every,
+            parallel_downloads: get(self.parallel_downloads, "parallel_downloads")?,
+            run_every,
             output_dir: get(self.output_dir, "output_dir")?,
             random: get(self.random, "random")?,
+            weighted_by: self.weighted_by,
+            temperature,
+            seed: self.seed,
             subreddits: get(self.subreddits, "subreddits")?,
             min_res: get(self.min_res, "min-res").ok(),
+            max_size: get(self.max_size, "max_size")?,
+            display_formats: get(self.display_formats, "display_formats")?,
+            convert_to,
+            animated: get(self.animated, "animated")?,
+            allow_nsfw: get(self.allow_nsfw, "allow_nsfw")?,
+            allow_spoilers: get(self.allow_spoilers, "allow_spoilers")?,
+            allow_stickied: get(self.allow_stickied, "allow_stickied")?,
+            min_score: self.min_score,
+            min_upvote_ratio: self.min_upvote_ratio,
+            min_comments: self.min_comments,
+            max_age,
+            min_age,
+            include_titles: patterns(self.include_titles)?,
+            exclude_titles: patterns(self.exclude_titles)?,
+            prefer_titles: patterns(self.prefer_titles)?,
+            match_flair: get(self.match_flair, "match_flair")?,
+            allowed_authors: self.allowed_authors.unwrap_or_default(),
+            blocked_authors: self.blocked_authors.unwrap_or_default(),
+            allowed_domains: self.allowed_domains.unwrap_or_default(),
+            blocked_domains: self.blocked_domains.unwrap_or_default(),
+            duplicate_threshold: get(self.duplicate_threshold, "duplicate_threshold")?,
+            min_brightness: self.min_brightness,
+            max_brightness: self.max_brightness,
+            max_contrast: self.max_contrast,
+            prefer_hue: self.prefer_hue,
+            target_brightness: self.target_brightness,
+            dimming: get(self.dimming, "dimming")?,
+            rules,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            run_at,
+            jitter,
+            reddit_url: get(self.reddit_url, "reddit_url")?,
+            retries: get(self.retries, "retries")?,
+            retry_backoff,
+            request_interval,
+            user_agent: get(self.user_agent, "user_agent")?,
+            connect_timeout,
+            read_timeout,
+            proxy: self.proxy,
+            ca_certificate: self.ca_certificate,
+            command_line: None,
         })
     }
+}
 
+impl ConfigFile {
     pub fn load_from_file<P: AsRef<Path>>(file: P) -> Result<Self, String> {
         let file: &Path = file.as_ref();
-        let content = ::std::fs::read_to_string(file).unwrap();
+        let content = ::std::fs::read_to_string(file)
+            .map_err(|error| format!("could not read config: {}", error))?;
         let config = ::serde_json::from_str(&content)
             .map_err(|error| format!("could not parse config: {}", error))?;
         Ok(config)
     }
+
+    /// The settings of this file with the profile [name] layered on top of them
+    pub fn layers(self, profile: Option<&str>) -> Result<Vec<Settings>, String> {
+        let ConfigFile { settings, profiles } = self;
+        let find_profile = |name: &str| {
+            profiles
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("Profile '{}' not found in {}", name, CONFIG_FILE_NAME))
+        };
+
+        let mut layers = vec![settings];
+        if let Some(name) = profile {
+            layers.push(find_profile(name)?);
+            info!("Using profile '{}'", name);
+        }
+
+        // rules referring to a profile get its settings beneath their own
+        for layer in layers.iter_mut() {
+            for rule in layer.rules.iter_mut().flat_map(|rules| rules.iter_mut()) {
+                if let Some(name) = rule.profile.take() {
+                    let mut overrides = vec![find_profile(&name)?];
+                    overrides.extend(rule.settings.take());
+                    rule.settings = Some(Settings::combine(overrides)?);
+                }
+            }
+        }
+
+        Ok(layers)
+    }
+}
+
+impl From<Configuration> for Settings {
+    fn from(config: Configuration) -> Self {
+        fn to_strings(patterns: &[Pattern]) -> Vec<String> {
+            patterns.iter().map(ToString::to_string).collect()
+        }
+
+        Settings {
+            mode: Some(config.mode),
+            min_ratio: config.min_ratio,
+            max_ratio: config.max_ratio,
+            query_size: Some(config.query_size),
+            parallel_downloads: Some(config.parallel_downloads),
+            run_every: config.run_every.map(|schedule| schedule.to_string()),
+            output_dir: Some(config.output_dir),
+            random: Some(config.random),
+            weighted_by: config.weighted_by,
+            temperature: Some(config.temperature),
+            seed: config.seed,
+            subreddits: Some(config.subreddits),
+            min_res: config.min_res,
+            max_size: Some(config.max_size),
+            display_formats: Some(config.display_formats),
+            convert_to: Some(config.convert_to),
+            animated: Some(config.animated),
+            allow_nsfw: Some(config.allow_nsfw),
+            allow_spoilers: Some(config.allow_spoilers),
+            allow_stickied: Some(config.allow_stickied),
+            min_score: config.min_score,
+            min_upvote_ratio: config.min_upvote_ratio,
+            min_comments: config.min_comments,
+            max_age: config.max_age.map(format_duration),
+            min_age: config.min_age.map(format_duration),
+            include_titles: Some(to_strings(&config.include_titles)),
+            exclude_titles: Some(to_strings(&config.exclude_titles)),
+            prefer_titles: Some(to_strings(&config.prefer_titles)),
+            match_flair: Some(config.match_flair),
+            allowed_authors: Some(config.allowed_authors),
+            blocked_authors: Some(config.blocked_authors),
+            allowed_domains: Some(config.allowed_domains),
+            blocked_domains: Some(config.blocked_domains),
+            duplicate_threshold: Some(config.duplicate_threshold),
+            min_brightness: config.min_brightness,
+            max_brightness: config.max_brightness,
+            max_contrast: config.max_contrast,
+            prefer_hue: config.prefer_hue,
+            target_brightness: config.target_brightness,
+            dimming: Some(config.dimming),
+            rules: Some(config.rules),
+            latitude: config.latitude,
+            longitude: config.longitude,
+            run_at: Some(config.run_at),
+            jitter: config.jitter.map(format_duration),
+            reddit_url: Some(config.reddit_url),
+            retries: Some(config.retries),
+            retry_backoff: Some(format_duration(config.retry_backoff)),
+            request_interval: Some(format_duration(config.request_interval)),
+            user_agent: Some(config.user_agent),
+            connect_timeout: Some(format_duration(config.connect_timeout)),
+            read_timeout: Some(format_duration(config.read_timeout)),
+            proxy: config.proxy,
+            ca_certificate: config.ca_certificate,
+        }
+    }
 }
 
 impl Configuration {
+    /// The coordinates (latitude, longitude) used for computing solar events
+    pub fn location(&self) -> Option<(f32, f32)> {
+        match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => Some((latitude, longitude)),
+            _ => None,
+        }
+    }
+
+    /// 'true' if wallpapers are filtered by their brightness, contrast or colors
+    pub fn filters_colors(&self) -> bool {
+        self.min_brightness.is_some()
+            || self.max_brightness.is_some()
+            || self.max_contrast.is_some()
+            || self.prefer_hue.is_some()
+    }
+
+    /// This configuration with the settings of every rule applying at [time] layered on top,
+    /// except for the ones given as command-line arguments
+    pub fn at(&self, time: &DateTime<Local>) -> Result<Configuration, String> {
+        let mut rules = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.applies_at(time, self.location()) {
+                info!("Rule #{} applies", index + 1);
+                rules.push(rule);
+            }
+        }
+        self.with_rules(&rules)
+    }
+
+    /// This configuration with the settings of [rules] layered on top of it,
+    /// but beneath the command-line arguments
+    fn with_rules(&self, rules: &[&Rule]) -> Result<Configuration, String> {
+        let mut settings = vec![Settings::from(self.clone())];
+        settings.extend(rules.iter().filter_map(|rule| rule.settings.clone()));
+        settings.extend(self.command_line.clone());
+
+        let mut config = Settings::combine(settings)?.into_config()?;
+        config.rules = self.rules.clone();
+        config.command_line = self.command_line.clone();
+        Ok(config)
+    }
+
+    /// The path of the configuration file, which may not exist
+    pub fn file() -> PathBuf {
+        ::utils::install_dir().join(CONFIG_FILE_NAME)
+    }
+
     pub fn init(matches: &ArgMatches) -> Result<Configuration, String> {
-        let file = ::utils::install_dir().join(CONFIG_FILE_NAME);
+        let file = Configuration::file();
 
         let cli_settings = Settings::from_matches(matches)?;
         let default_settings = Settings::default();
+        let profile = matches
+            .value_of("profile")
+            .map(str::to_owned)
+            .or_else(|| ::std::env::var(PROFILE_ENV_VAR).ok());
 
-        let settings = if file.is_file() {
+        let mut settings = vec![default_settings];
+        if file.is_file() {
             info!(
                 "Loading configuration file {}...",
                 file.file_name().unwrap().to_str().unwrap()
             );
-            let file_config = Settings::load_from_file(file)?;
-            vec![default_settings, file_config, cli_settings]
-        } else {
-            vec![default_settings, cli_settings]
-        };
+            let file_config = ConfigFile::load_from_file(file)?;
+            settings.extend(file_config.layers(profile.as_deref())?);
+        } else if let Some(name) = profile {
+            return Err(format!(
+                "Profile '{}' requested, but there is no {}",
+                name, CONFIG_FILE_NAME
+            ));
+        }
+        Configuration::from_layers(settings, cli_settings)
+    }
+
+    /// The configuration made of [settings] with [command_line] layered on top
+    fn from_layers(
+        mut settings: Vec<Settings>,
+        command_line: Settings,
+    ) -> Result<Configuration, String> {
+        settings.push(command_line.clone());
+
+        let mut config = Settings::combine(settings)?.into_config()?;
+        config.command_line = Some(command_line);
 
-        let config = Settings::combine(settings)?.into_config()?;
+        // invalid settings of rules must not make searches fail later on
+        for (index, rule) in config.rules.iter().enumerate() {
+            config
+                .with_rules(&[rule])
+                .map_err(|error| format!("Invalid rule #{}: {}", index + 1, error))?;
+        }
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigFile, Configuration, Settings};
+    use chrono::{Duration, Local};
+    use clap::ArgMatches;
+    use reddit::Mode;
+    use rules::Rule;
+
+    fn rule(json: &str) -> Rule {
+        ::serde_json::from_str(json).unwrap()
+    }
+
+    fn file_settings(rules: Vec<Rule>) -> Settings {
+        Settings {
+            mode: Some(Mode::New),
+            subreddits: Some(vec!["wallpapers".to_owned()]),
+            rules: Some(rules),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn command_line_arguments_take_precedence_over_rules() {
+        let rules = vec![rule(
+            r#"{"settings": {"subreddits": ["EarthPorn"], "min_score": 10}}"#,
+        )];
+        let mut command_line = Settings::from_matches(&ArgMatches::default()).unwrap();
+        command_line.subreddits = Some(vec!["SkyPorn".to_owned()]);
+
+        let config = Configuration::from_layers(vec![file_settings(rules)], command_line)
+            .and_then(|config| config.at(&Local::now()))
+            .unwrap();
+
+        assert_eq!(config.subreddits, vec!["SkyPorn".to_owned()]);
+        assert_eq!(config.min_score, Some(10));
+    }
+
+    #[test]
+    fn rules_override_the_configuration_file() {
+        let rules = vec![rule(r#"{"settings": {"subreddits": ["EarthPorn"]}}"#)];
+        let command_line = Settings::from_matches(&ArgMatches::default()).unwrap();
+
+        let config = Configuration::from_layers(vec![file_settings(rules)], command_line)
+            .and_then(|config| config.at(&Local::now()))
+            .unwrap();
+
+        assert_eq!(config.subreddits, vec!["EarthPorn".to_owned()]);
+    }
+
+    #[test]
+    fn rules_must_not_change_the_schedule() {
+        let rules = vec![rule(r#"{"from": "22:00", "settings": {"run_every": "1h"}}"#)];
+        let command_line = Settings::from_matches(&ArgMatches::default()).unwrap();
+
+        let error = Configuration::from_layers(vec![file_settings(rules)], command_line)
+            .unwrap_err();
+        assert!(error.contains("run_every"));
+    }
+
+    fn config_file(json: &str) -> ConfigFile {
+        ::serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn rules_get_the_settings_of_their_profile_beneath_their_own() {
+        let file = config_file(
+            r#"{
+                "rules": [{"from": "22:00", "profile": "night", "settings": {"min_score": 10}}],
+                "profiles": {"night": {"subreddits": ["SkyPorn"], "min_score": 5}}
+            }"#,
+        );
+
+        let layers = file.layers(None).unwrap();
+        let rule = &layers[0].rules.as_ref().unwrap()[0];
+        let settings = rule.settings.as_ref().unwrap();
+
+        assert!(rule.profile.is_none());
+        assert_eq!(settings.subreddits, Some(vec!["SkyPorn".to_owned()]));
+        assert_eq!(settings.min_score, Some(10));
+    }
+
+    #[test]
+    fn selected_profile_is_layered_on_top_of_the_file() {
+        let file = config_file(r#"{"min_score": 5, "profiles": {"laptop": {"min_score": 7}}}"#);
+
+        let layers = file.layers(Some("laptop")).unwrap();
+
+        assert_eq!(layers.len(), 2);
+        assert_eq!(Settings::combine(layers).unwrap().min_score, Some(7));
+    }
+
+    #[test]
+    fn unknown_profiles_are_rejected() {
+        let selected = config_file(r#"{"profiles": {"laptop": {}}}"#).layers(Some("desktop"));
+        assert!(selected.unwrap_err().contains("'desktop'"));
+
+        let in_rule = config_file(r#"{"rules": [{"profile": "night"}]}"#).layers(None);
+        assert!(in_rule.unwrap_err().contains("'night'"));
+    }
+
+    #[test]
+    fn invalid_settings_of_rules_are_rejected_when_loading() {
+        let command_line = Settings::from_matches(&ArgMatches::default()).unwrap();
+        for settings in [r#"{"target_brightness": 3}"#, r#"{"exclude_titles": ["/(/"]}"#].iter() {
+            let rules = vec![rule(&format!(r#"{{"from": "22:00", "settings": {}}}"#, settings))];
+
+            let error = Configuration::from_layers(vec![file_settings(rules)], command_line.clone())
+                .unwrap_err();
+            assert!(error.starts_with("Invalid rule #1"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn durations_are_kept_when_rules_apply() {
+        let rules = vec![rule(r#"{"settings": {"min_score": 10}}"#)];
+        let mut settings = file_settings(rules);
+        settings.max_age = Some("1d12h".to_owned());
+        settings.jitter = Some("90s".to_owned());
+        let command_line = Settings::from_matches(&ArgMatches::default()).unwrap();
+
+        let config = Configuration::from_layers(vec![settings], command_line)
+            .and_then(|config| config.at(&Local::now()))
+            .unwrap();
+
+        assert_eq!(config.max_age, Some(Duration::hours(36)));
+        assert_eq!(config.jitter, Some(Duration::seconds(90)));
+        assert_eq!(config.read_timeout, Duration::seconds(30));
+    }
+}
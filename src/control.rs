@@ -0,0 +1,217 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+#[cfg(unix)]
+pub use self::unix::{is_running, send, Request, Server};
+#[cfg(not(unix))]
+pub use self::unsupported::{is_running, send, Request, Server};
+
+pub const SOCKET_FILE_NAME: &str = "control.sock";
+
+/// A command sent to a running daemon through its control socket
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    Next,
+    Previous,
+    Pause,
+    Resume,
+    Status,
+    Current,
+    ReloadConfig,
+    Quit,
+}
+
+impl Command {
+    pub fn from_identifier(id: &str) -> Option<Self> {
+        use control::Command::*;
+        match &*id.to_lowercase() {
+            "next" => Some(Next),
+            "previous" => Some(Previous),
+            "pause" => Some(Pause),
+            "resume" => Some(Resume),
+            "status" => Some(Status),
+            "current" => Some(Current),
+            "reload-config" | "reload" => Some(ReloadConfig),
+            "quit" => Some(Quit),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        use control::Command::*;
+
+        let to_str = match self {
+            Next => "next",
+            Previous => "previous",
+            Pause => "pause",
+            Resume => "resume",
+            Status => "status",
+            Current => "current",
+            ReloadConfig => "reload-config",
+            Quit => "quit",
+        };
+
+        write!(f, "{}", to_str)
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{Command, SOCKET_FILE_NAME};
+    use std::fs::remove_file;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+
+    /// How long a client waits for the daemon to answer
+    const TIMEOUT_SECS: u64 = 10;
+
+    /// How long the daemon waits for a client to send its command
+    const READ_TIMEOUT_SECS: u64 = 5;
+
+    /// A command received by the daemon, which has to be answered using [reply]
+    pub struct Request {
+        pub command: Command,
+        stream: UnixStream,
+    }
+
+    impl Request {
+        pub fn reply<S: Into<String>>(mut self, response: S) {
+            self.stream
+                .write_all(response.into().as_bytes())
+                .map_err(|error| warn!("Could not reply to '{}': {}", self.command, error))
+                .ok();
+        }
+    }
+
+    /// The listening end of the control socket, removed when dropped
+    pub struct Server {
+        path: PathBuf,
+        requests: Receiver<Request>,
+    }
+
+    impl Server {
+        /// Creates the control socket and accepts connections on a background thread.
+        /// Fails if another instance is running already.
+        pub fn start() -> Result<Option<Server>, String> {
+            let path = ::utils::install_dir().join(SOCKET_FILE_NAME);
+
+            if path.exists() {
+                if is_running() {
+                    return Err("Another instance is already running".to_owned());
+                }
+                remove_file(&path)
+                    .map_err(|error| format!("Could not remove stale socket: {}", error))?;
+            }
+
+            let listener = UnixListener::bind(&path)
+                .map_err(|error| format!("Could not create control socket: {}", error))?;
+            let (sender, requests) = channel();
+
+            thread::spawn(move || {
+                for stream in listener.incoming().filter_map(Result::ok) {
+                    // a client which doesn't send anything must not block the others
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        handle(stream, &sender)
+                            .map_err(|error| warn!("Control connection failed: {}", error))
+                            .ok();
+                    });
+                }
+            });
+
+            Ok(Some(Server { path, requests }))
+        }
+
+        /// The next command which has been received, if any
+        pub fn poll(&self) -> Option<Request> {
+            self.requests.try_recv().ok()
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            remove_file(&self.path).ok();
+        }
+    }
+
+    /// 'true' if a daemon accepts commands
+    pub fn is_running() -> bool {
+        UnixStream::connect(::utils::install_dir().join(SOCKET_FILE_NAME)).is_ok()
+    }
+
+    /// Sends [command] to the running daemon and returns its response
+    /// or [None] if no daemon is running
+    pub fn send(command: Command) -> Option<Result<String, String>> {
+        let path = ::utils::install_dir().join(SOCKET_FILE_NAME);
+        let mut stream = UnixStream::connect(path).ok()?;
+
+        let mut response = String::new();
+        let result = stream
+            .set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))
+            .and_then(|_| writeln!(stream, "{}", command))
+            .and_then(|_| stream.read_to_string(&mut response))
+            .map(|_| response)
+            .map_err(|error| format!("Could not talk to daemon: {}", error));
+        Some(result)
+    }
+
+    fn handle(mut stream: UnixStream, sender: &Sender<Request>) -> Result<(), String> {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)))
+            .map_err(|error| format!("could not set timeout: {}", error))?;
+
+        let mut line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut line)
+            .map_err(|error| format!("could not read command: {}", error))?;
+
+        match Command::from_identifier(line.trim()) {
+            Some(command) => sender
+                .send(Request { command, stream })
+                .map_err(|_| "daemon stopped".to_owned()),
+            None => stream
+                .write_all(format!("Unknown command '{}'", line.trim()).as_bytes())
+                .map_err(|error| format!("could not send response: {}", error)),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod unsupported {
+    use super::Command;
+
+    pub struct Request {
+        pub command: Command,
+    }
+
+    impl Request {
+        pub fn reply<S: Into<String>>(self, _response: S) {}
+    }
+
+    pub struct Server;
+
+    impl Server {
+        /// There is no control socket on this platform
+        pub fn start() -> Result<Option<Server>, String> {
+            Ok(None)
+        }
+
+        pub fn poll(&self) -> Option<Request> {
+            None
+        }
+    }
+
+    /// Whether a daemon is running can't be told on this platform
+    pub fn is_running() -> bool {
+        false
+    }
+
+    pub fn send(_command: Command) -> Option<Result<String, String>> {
+        None
+    }
+}
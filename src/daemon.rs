@@ -0,0 +1,217 @@
+use chrono::{Local, Utc};
+use clap::ArgMatches;
+use configuration::Configuration;
+use control::{Command, Request, Server};
+use scheduler::{Scheduler, SingleFlight};
+use std::fs::metadata;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+use wallpaper::Wallpaper;
+
+/// The maximum amount of wallpapers remembered for 'previous'
+const HISTORY_SIZE: usize = 20;
+
+/// Searches for new wallpapers according to the schedule of the configuration
+/// and accepts commands through the control socket
+pub struct Daemon<'a> {
+    config: Configuration,
+    matches: &'a ArgMatches<'a>,
+    scheduler: Scheduler,
+    single_flight: SingleFlight,
+    /// the wallpapers which have been set, the current one last
+    history: Arc<Mutex<Vec<Wallpaper>>>,
+    paused: bool,
+    /// the modification time of the configuration file when it was last loaded
+    config_modified: Option<SystemTime>,
+}
+
+impl<'a> Daemon<'a> {
+    /// [matches] are the command-line arguments used when reloading the configuration
+    pub fn new(config: Configuration, matches: &'a ArgMatches<'a>) -> Self {
+        Daemon {
+            scheduler: Scheduler::new(&config),
+            config,
+            matches,
+            single_flight: SingleFlight::default(),
+            history: Arc::new(Mutex::new(Vec::new())),
+            paused: false,
+            config_modified: config_modified(),
+        }
+    }
+
+    pub fn run(mut self) {
+        let server = match Server::start() {
+            Ok(Some(server)) => Some(server),
+            Ok(None) => {
+                warn!("Control socket unavailable on this platform");
+                None
+            }
+            Err(error) => {
+                // the log file may belong to the running instance, so the error is printed too
+                error!("{}", error);
+                eprintln!("{}", error);
+                exit(1);
+            }
+        };
+
+        self.search();
+
+        loop {
+            while let Some(request) = server.as_ref().and_then(Server::poll) {
+                self.handle(request);
+            }
+
+            if ::shutdown::requested() {
+                info!("Waiting for the current search to finish...");
+                while self.single_flight.is_running() {
+                    sleep(Duration::from_millis(100));
+                }
+                return;
+            }
+
+            let modified = config_modified();
+            if modified != self.config_modified {
+                info!("Configuration file changed");
+                self.config_modified = modified;
+                self.reload();
+            }
+
+            if self.scheduler.tick(&self.config, Utc::now()) && !self.paused {
+                self.search();
+            }
+
+            sleep(Duration::from_millis(500));
+        }
+    }
+
+    /// Starts searching for a new wallpaper in the background, returns 'false' if already searching
+    fn search(&self) -> bool {
+        let config = self.config.clone();
+        let history = self.history.clone();
+
+        let started = self.single_flight.spawn(move || {
+            if let Some(wallpaper) = ::run_once(&config) {
+                let mut history = history.lock().unwrap();
+                history.push(wallpaper);
+                if history.len() > HISTORY_SIZE {
+                    history.remove(0);
+                }
+            }
+        });
+
+        if !started {
+            warn!("The previous search is still running, skipping this one");
+        }
+        started
+    }
+
+    /// Executes the command of [request] and replies to it
+    fn handle(&mut self, request: Request) {
+        info!("Received command '{}'", request.command);
+
+        match request.command {
+            Command::Next => {
+                if self.search() {
+                    request.reply("Searching for a new wallpaper...")
+                } else {
+                    request.reply("Already searching for a new wallpaper")
+                }
+            }
+            Command::Previous => request.reply(self.previous()),
+            Command::Pause => {
+                self.paused = true;
+                request.reply("Paused")
+            }
+            Command::Resume => {
+                self.paused = false;
+                request.reply("Resumed")
+            }
+            Command::Status => request.reply(self.status()),
+            Command::Current => request.reply(self.current()),
+            Command::ReloadConfig => request.reply(self.reload()),
+            Command::Quit => {
+                // like on a signal, the current search is finished first
+                ::shutdown::request();
+                request.reply("Quitting")
+            }
+        }
+    }
+
+    fn previous(&self) -> String {
+        let mut history = self.history.lock().unwrap();
+        if history.len() < 2 {
+            return "There is no previous wallpaper".to_owned();
+        }
+
+        history.pop();
+        let previous = history.last().unwrap();
+        match previous.set() {
+            Ok(()) => {
+                ::index::remember_set(&self.config, previous);
+                format!("Restored '{}'", previous.title)
+            }
+            Err(error) => format!("Could not restore '{}': {}", previous.title, error),
+        }
+    }
+
+    fn current(&self) -> String {
+        match self.history.lock().unwrap().last() {
+            Some(wallpaper) => match wallpaper.file {
+                Some(ref file) => format!("{}\n{}", wallpaper.title, file.display()),
+                None => wallpaper.title.clone(),
+            },
+            None => "No wallpaper has been set yet".to_owned(),
+        }
+    }
+
+    fn status(&self) -> String {
+        let state = if self.paused { "paused" } else { "running" };
+        let search = if self.single_flight.is_running() {
+            "in progress"
+        } else {
+            "idle"
+        };
+        let next_run = match self.scheduler.next_run() {
+            Some(time) => time.with_timezone(&Local).to_rfc2822(),
+            None => "-".to_owned(),
+        };
+        let current = match self.history.lock().unwrap().last() {
+            Some(wallpaper) => wallpaper.title.clone(),
+            None => "-".to_owned(),
+        };
+
+        format!(
+            "state: {}\nsearch: {}\nnext run: {}\ncurrent: {}",
+            state, search, next_run, current
+        )
+    }
+
+    /// Loads the configuration again, keeping the command-line arguments.
+    /// The current configuration stays in use if the new one is invalid.
+    fn reload(&mut self) -> String {
+        match Configuration::init(self.matches) {
+            Ok(config) => {
+                info!("{:?}", config);
+                let schedule_changed = config.run_every.as_ref().map(ToString::to_string)
+                    != self.config.run_every.as_ref().map(ToString::to_string);
+                self.config = config;
+                if schedule_changed {
+                    self.scheduler.reschedule(&self.config, Utc::now());
+                }
+                "Configuration reloaded".to_owned()
+            }
+            Err(error) => {
+                error!("Could not reload configuration: {}", error);
+                format!("Could not reload configuration: {}", error)
+            }
+        }
+    }
+}
+
+fn config_modified() -> Option<SystemTime> {
+    metadata(Configuration::file())
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
@@ -0,0 +1,288 @@
+use colors::luminance;
+use exif::{In, Tag};
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::grayscale;
+use image::{AnimationDecoder, DynamicImage, ImageFormat, RgbaImage};
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::io::{BufReader, Cursor};
+use std::path::{Path, PathBuf};
+use utils::write_atomically;
+
+/// The directory inside the output directory in which derived images are stored
+pub const DERIVED_DIR: &str = "derived";
+
+/// The quality of JPEG images which are created
+const JPEG_QUALITY: u8 = 92;
+
+/// The maximum amount of frames of an animation which are compared
+const MAX_FRAMES: usize = 100;
+
+/// The supported formats which can't be decoded, so that images can only be set as they are
+const UNDECODABLE_FORMATS: [&str; 1] = ["avif"];
+
+/// How animated images are handled
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Animated {
+    /// animated images are not used
+    Skip,
+    /// the sharpest frame is used as a still image
+    Still,
+}
+
+impl Animated {
+    pub fn from_identifier(id: &str) -> Option<Self> {
+        match &*id.to_lowercase() {
+            "skip" => Some(Animated::Skip),
+            "still" => Some(Animated::Still),
+            _ => None,
+        }
+    }
+}
+
+/// How images are darkened to reach the target brightness
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dimming {
+    /// the whole image is darkened evenly
+    Overlay,
+    /// the edges are darkened more than the center
+    Vignette,
+    /// the whole image is darkened evenly and its colors are faded
+    Desaturate,
+}
+
+impl Dimming {
+    pub fn from_identifier(id: &str) -> Option<Self> {
+        match &*id.to_lowercase() {
+            "overlay" => Some(Dimming::Overlay),
+            "vignette" => Some(Dimming::Vignette),
+            "desaturate" => Some(Dimming::Desaturate),
+            _ => None,
+        }
+    }
+
+    fn identifier(self) -> &'static str {
+        match self {
+            Dimming::Overlay => "overlay",
+            Dimming::Vignette => "vignette",
+            Dimming::Desaturate => "desaturate",
+        }
+    }
+}
+
+/// 'true' if images in [format] can be decoded for converting them or analyzing their colors
+pub fn can_decode(format: &str) -> bool {
+    !UNDECODABLE_FORMATS.contains(&format)
+}
+
+/// The path of the image derived from [original] with the extension [format]
+pub fn path_for(original: &Path, format: &str) -> PathBuf {
+    let dir = original.parent().unwrap_or_else(|| Path::new("")).join(DERIVED_DIR);
+    let mut name = OsString::from(original.file_name().unwrap());
+    name.push(".");
+    name.push(format);
+    dir.join(name)
+}
+
+/// Converts [original] into an image with the [format] 'jpeg' or 'png', turned upright
+/// according to the EXIF [orientation], and returns its path.
+/// Images which have been converted before are reused.
+pub fn convert(original: &Path, format: &str, orientation: u32) -> Result<PathBuf, String> {
+    let path = if orientation > 1 {
+        path_for(original, &format!("upright.{}", format))
+    } else {
+        path_for(original, format)
+    };
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    let image =
+        image::open(original).map_err(|error| format!("could not decode image: {}", error))?;
+    write(&upright(image, orientation), &path, format)?;
+    Ok(path)
+}
+
+/// The EXIF orientation of the image [data], from 1 (upright) to 8
+pub fn orientation(data: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        }).filter(|orientation| (1..=8).contains(orientation))
+        .unwrap_or(1)
+}
+
+/// 'true' if an image with the EXIF [orientation] is turned by 90° or 270°
+pub fn is_transposed(orientation: u32) -> bool {
+    orientation >= 5
+}
+
+/// [image] rotated and mirrored as described by its EXIF [orientation]
+pub fn upright(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Darkens [source], which is [original] or an image derived from it, using [dimming] until its
+/// mean luminance is [target] and returns the path of the created image with the [format]
+/// 'jpeg' or 'png'. Images which are dark enough already are returned unchanged.
+pub fn dim(
+    original: &Path,
+    source: &Path,
+    target: f32,
+    dimming: Dimming,
+    format: &str,
+) -> Result<PathBuf, String> {
+    let name = format!("{}-{:.2}.{}", dimming.identifier(), target, format);
+    let path = path_for(original, &name);
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    let mut image = image::open(source)
+        .map_err(|error| format!("could not decode image: {}", error))?
+        .to_rgb8();
+    let (width, height) = image.dimensions();
+    let count = f64::from(width) * f64::from(height);
+
+    // the squared distance of a pixel to the center, from 0 to 1 in the corners
+    let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    let distance = |x: u32, y: u32| {
+        let dx = (x as f32 + 0.5 - center_x) / center_x;
+        let dy = (y as f32 + 0.5 - center_y) / center_y;
+        (dx * dx + dy * dy) / 2.0
+    };
+
+    let (mut brightness, mut edges) = (0.0, 0.0);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let luminance = f64::from(luminance(pixel));
+        brightness += luminance;
+        edges += luminance * f64::from(distance(x, y));
+    }
+    let (brightness, edges) = ((brightness / count) as f32, (edges / count) as f32);
+    if brightness <= target {
+        return Ok(source.to_owned());
+    }
+
+    // the vignette darkens the corners by up to 100 percent, the rest is darkened evenly
+    let vignette = match dimming {
+        Dimming::Vignette if edges > 0.0 => ((brightness - target) / edges).min(1.0),
+        _ => 0.0,
+    };
+    let factor = target / (brightness - vignette * edges);
+    let fade = match dimming {
+        Dimming::Desaturate => 1.0 - factor,
+        _ => 0.0,
+    };
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let scale = factor * (1.0 - vignette * distance(x, y));
+        let gray = luminance(pixel);
+        for channel in pixel.0.iter_mut() {
+            let value = f32::from(*channel) / 255.0;
+            let faded = value + (gray - value) * fade;
+            *channel = (faded * scale * 255.0).round().min(255.0) as u8;
+        }
+    }
+
+    write(&DynamicImage::ImageRgb8(image), &path, format)?;
+    Ok(path)
+}
+
+/// Extracts the sharpest frame of the animated [original] with the [format] 'gif' or 'webp'
+/// and returns the path of the created PNG image. Frames extracted before are reused.
+pub fn still_frame(original: &Path, format: &str) -> Result<PathBuf, String> {
+    let path = path_for(original, "still.png");
+    if path.is_file() {
+        return Ok(path);
+    }
+
+    let file = File::open(original).map_err(|e| format!("could not open file: {}", e))?;
+    let reader = BufReader::new(file);
+    let frames = match format {
+        "gif" => GifDecoder::new(reader).map(AnimationDecoder::into_frames),
+        "webp" => WebPDecoder::new(reader).map(AnimationDecoder::into_frames),
+        _ => return Err(format!("{} images are not animated", format)),
+    }.map_err(|e| format!("could not decode image: {}", e))?;
+
+    let (_, sharpest) = frames
+        .take(MAX_FRAMES)
+        .filter_map(Result::ok)
+        .map(|frame| frame.into_buffer())
+        .map(|frame| (sharpness(&frame), frame))
+        .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+        .ok_or_else(|| "no frame could be decoded".to_owned())?;
+
+    write(&DynamicImage::ImageRgba8(sharpest), &path, "png")?;
+    Ok(path)
+}
+
+/// The variance of the laplacian of the luminance of [image], which is higher for sharper images
+fn sharpness(image: &RgbaImage) -> f64 {
+    let luma = grayscale(image);
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let at = |x: u32, y: u32| f64::from(luma.get_pixel(x, y)[0]);
+    let (mut sum, mut sum_of_squares) = (0.0, 0.0);
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let neighbours = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1);
+            let laplacian = neighbours - 4.0 * at(x, y);
+            sum += laplacian;
+            sum_of_squares += laplacian * laplacian;
+        }
+    }
+
+    let count = f64::from((width - 2) * (height - 2));
+    let mean = sum / count;
+    sum_of_squares / count - mean * mean
+}
+
+/// Removes all images derived from [original]
+pub fn remove_all(original: &Path) {
+    let dir = original.parent().unwrap_or_else(|| Path::new("")).join(DERIVED_DIR);
+    let name = original.file_name().unwrap().to_string_lossy().into_owned();
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        if entry.file_name().to_string_lossy().starts_with(&name) {
+            remove_file(entry.path()).ok();
+        }
+    }
+}
+
+/// Writes [image] to [path] using the [format] 'jpeg' or 'png'
+pub fn write(image: &DynamicImage, path: &Path, format: &str) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir).map_err(|e| format!("could not create path: {}", e))?;
+    }
+
+    write_atomically(path, |writer| {
+        match format {
+            "png" => image.write_to(writer, ImageFormat::Png),
+            _ => JpegEncoder::new_with_quality(writer, JPEG_QUALITY).encode_image(&image.to_rgb8()),
+        }.map_err(|e| format!("could not encode image: {}", e))
+    })
+}
@@ -0,0 +1,298 @@
+use chrono::{DateTime, Utc};
+use configuration::Configuration;
+use rand::{thread_rng, Rng};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Certificate, Proxy, StatusCode, Url};
+use std::fs::read;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The longest delay requested through 'Retry-After' which is honored
+const MAX_RETRY_AFTER_SECS: u64 = 300;
+
+/// The longest delay between retries, however often a request failed
+const MAX_BACKOFF_SECS: u64 = 3600;
+
+/// The point in time of the last request to every host
+static LAST_REQUESTS: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+/// Sends requests, retrying failed ones with an exponential backoff
+/// and keeping a minimum interval between requests to the same host
+pub struct Http {
+    client: Client,
+    retries: u32,
+    backoff: Duration,
+    request_interval: Duration,
+}
+
+impl Http {
+    pub fn new(config: &Configuration) -> Result<Self, String> {
+        let duration = |duration: &::chrono::Duration| duration.to_std().map_err(|e| e.to_string());
+
+        // the timeout of the blocking client applies to every single read of the body
+        let mut builder = Client::builder()
+            .user_agent(config.user_agent.clone())
+            .connect_timeout(duration(&config.connect_timeout)?)
+            .timeout(duration(&config.read_timeout)?);
+
+        // without a proxy of its own, the client uses the ones of the environment
+        // like 'HTTPS_PROXY' and skips the hosts in 'NO_PROXY'
+        if let Some(ref proxy) = config.proxy {
+            let proxy =
+                Proxy::all(proxy).map_err(|error| format!("Invalid proxy {}: {}", proxy, error))?;
+            builder = builder.no_proxy().proxy(proxy);
+        }
+
+        if let Some(ref path) = config.ca_certificate {
+            let data = read(path)
+                .map_err(|error| format!("could not read certificate {}: {}", path, error))?;
+            let certificate = Certificate::from_pem(&data)
+                .or_else(|_| Certificate::from_der(&data))
+                .map_err(|error| format!("invalid certificate {}: {}", path, error))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        let client = builder
+            .build()
+            .map_err(|error| format!("could not create HTTP client: {}", error))?;
+
+        Ok(Http {
+            client,
+            retries: config.retries,
+            backoff: duration(&config.retry_backoff)?,
+            request_interval: duration(&config.request_interval)?,
+        })
+    }
+
+    /// Requests [url], retrying on connection errors, rate limits and server errors
+    pub fn get(&self, url: &str) -> Result<Response, String> {
+        self.get_with_headers(url, HeaderMap::new())
+    }
+
+    /// Requests [url] like [get], sending additional [headers]
+    pub fn get_with_headers(&self, url: &str, headers: HeaderMap) -> Result<Response, String> {
+        let host = Url::parse(url)
+            .map_err(|error| format!("invalid url {}: {}", url, error))?
+            .host_str()
+            .unwrap_or_default()
+            .to_owned();
+
+        let mut attempt = 0;
+        loop {
+            self.wait_for_turn(&host);
+
+            let (error, retry_after) = match self.client.get(url).headers(headers.clone()).send() {
+                Ok(response) => {
+                    let status = response.status();
+                    if status.is_success() {
+                        return Ok(response);
+                    }
+                    let error = format!("{} responded with {}", url, status);
+                    if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                        return Err(error);
+                    }
+                    (error, retry_after(&response))
+                }
+                Err(error) => (format!("request to {} failed: {}", url, error), None),
+            };
+
+            if attempt >= self.retries || ::shutdown::requested() {
+                return Err(error);
+            }
+
+            let delay = retry_after.unwrap_or_else(|| self.backoff(attempt));
+            warn!("{}, retrying in {} seconds", error, delay.as_secs());
+            interruptible_sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    /// The delay before retrying for the [attempt]-th time: exponentially growing, with jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max = Duration::from_secs(MAX_BACKOFF_SECS);
+        let base = self
+            .backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .map_or(max, |base| base.min(max));
+        base.mul_f64(thread_rng().gen_range(0.5, 1.5))
+    }
+
+    /// Blocks until the last request to [host] is at least [request_interval] ago
+    fn wait_for_turn(&self, host: &str) {
+        let mut last_requests = LAST_REQUESTS.lock().unwrap();
+        let now = Instant::now();
+
+        let position = last_requests.iter().position(|(known, _)| known == host);
+        let start = match position.map(|index| last_requests[index].1) {
+            Some(last) if last + self.request_interval > now => last + self.request_interval,
+            _ => now,
+        };
+
+        match position {
+            Some(index) => last_requests[index].1 = start,
+            None => last_requests.push((host.to_owned(), start)),
+        }
+        drop(last_requests);
+
+        if start > now {
+            sleep(start - now);
+        }
+    }
+}
+
+/// Returns an error if [proxy] is no valid URL of a supported proxy
+pub fn check_proxy(proxy: &str) -> Result<(), String> {
+    let url = Url::parse(proxy).map_err(|error| format!("Invalid proxy {}: {}", proxy, error))?;
+    match url.scheme() {
+        "http" | "https" | "socks4" | "socks4a" | "socks5" | "socks5h" => Ok(()),
+        scheme => Err(format!("Unsupported proxy scheme '{}'", scheme)),
+    }
+}
+
+/// The delay requested by the 'Retry-After' header of [response], if any
+fn retry_after(response: &Response) -> Option<Duration> {
+    // the delay is either given in seconds or as an HTTP date
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => SystemTime::from(DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc))
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    };
+    Some(delay.min(Duration::from_secs(MAX_RETRY_AFTER_SECS)))
+}
+
+/// Sleeps for [duration], returning early if a shutdown is requested
+fn interruptible_sleep(duration: Duration) {
+    let end = Instant::now() + duration;
+    while Instant::now() < end && !::shutdown::requested() {
+        sleep(Duration::from_millis(100).min(end - Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Http, MAX_BACKOFF_SECS};
+    use reqwest::blocking::Client;
+    use reqwest::StatusCode;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+    const NOT_FOUND: &str =
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const UNAVAILABLE: &str =
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const TOO_MANY_REQUESTS: &str = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\n\
+                                     Content-Length: 0\r\nConnection: close\r\n\r\n";
+
+    /// Answers one request per connection on a local port with the next of [responses]
+    /// and returns the URL of the server and the points in time at which requests arrived
+    fn serve(responses: Vec<&'static str>) -> (String, Arc<Mutex<Vec<Instant>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+
+        let received = arrivals.clone();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                received.lock().unwrap().push(Instant::now());
+
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                stream.write_all(response.as_bytes()).ok();
+            }
+        });
+
+        (url, arrivals)
+    }
+
+    fn http(retries: u32) -> Http {
+        Http {
+            client: Client::builder().no_proxy().build().unwrap(),
+            retries,
+            backoff: Duration::from_millis(10),
+            request_interval: Duration::from_millis(0),
+        }
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let (url, arrivals) = serve(vec![UNAVAILABLE, UNAVAILABLE, OK]);
+
+        let response = http(3).get(&url).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(arrivals.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn retries_are_limited() {
+        let (url, arrivals) = serve(vec![UNAVAILABLE, UNAVAILABLE, OK]);
+
+        assert!(http(1).get(&url).is_err());
+        assert_eq!(arrivals.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retry_after_is_honored() {
+        let (url, arrivals) = serve(vec![TOO_MANY_REQUESTS, OK]);
+
+        let response = http(3).get(&url).unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let arrivals = arrivals.lock().unwrap();
+        assert_eq!(arrivals.len(), 2);
+        assert!(arrivals[1] - arrivals[0] >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let (url, arrivals) = serve(vec![NOT_FOUND, OK]);
+
+        let error = http(3).get(&url).unwrap_err();
+
+        assert!(error.contains("404"), "{}", error);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(arrivals.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut http = http(0);
+        http.backoff = Duration::from_secs(u64::MAX / 2);
+
+        let max = Duration::from_secs(MAX_BACKOFF_SECS);
+        assert!(http.backoff(0) <= max.mul_f64(1.5));
+        assert!(http.backoff(u32::MAX) <= max.mul_f64(1.5));
+    }
+
+    #[test]
+    fn requests_to_the_same_host_are_spaced() {
+        let mut http = http(0);
+        http.request_interval = Duration::from_millis(200);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            http.wait_for_turn("spaced.example.com");
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        // other hosts don't have to wait
+        let start = Instant::now();
+        http.wait_for_turn("other.example.com");
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
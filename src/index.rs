@@ -0,0 +1,338 @@
+use colors::Colors;
+use configuration::Configuration;
+use derived;
+use image::{self, DynamicImage};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{metadata, read, read_dir, read_to_string, remove_file, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use utils::write_atomically;
+use wallpaper::{Wallpaper, PARTIAL_EXTENSION, VALIDATOR_EXTENSION};
+
+/// The name of the index file inside the output directory
+pub const INDEX_FILE_NAME: &str = "index.json";
+
+/// The extension of the file which exists while the index is being changed
+pub const LOCK_EXTENSION: &str = "lock";
+
+/// The amount of wallpapers which have been set that new candidates are compared to
+const RECENT_SIZE: usize = 50;
+
+/// How long to wait for another process to finish changing the index
+const LOCK_TIMEOUT_SECS: u64 = 10;
+
+/// The age after which a lock file is considered left behind by a crashed process
+const STALE_LOCK_SECS: u64 = 60;
+
+/// Metadata about the images in the output directory and
+/// the perceptual hashes of the wallpapers which have been set or banned
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    /// the images in the output directory by their file name
+    #[serde(default)]
+    pub images: BTreeMap<String, Entry>,
+    /// the hashes of the wallpapers which have been set, the latest one last
+    #[serde(default)]
+    pub recent: Vec<u64>,
+    /// the hashes of the wallpapers which must not be set again
+    #[serde(default)]
+    pub banned: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// the difference hash of the image, see [hash_file]
+    pub hash: u64,
+    pub title: String,
+    pub url: Option<String>,
+    /// the colors of the image, if they have been analyzed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub colors: Option<Colors>,
+}
+
+impl Index {
+    /// Loads the index of the output directory [dir], which is empty if it doesn't exist yet
+    pub fn load<P: AsRef<Path>>(dir: P) -> Self {
+        let file = dir.as_ref().join(INDEX_FILE_NAME);
+        if !file.is_file() {
+            return Index::default();
+        }
+
+        read_to_string(&file)
+            .map_err(|error| error.to_string())
+            .and_then(|content| ::serde_json::from_str(&content).map_err(|e| e.to_string()))
+            .unwrap_or_else(|error| {
+                warn!("Could not load {}, starting over: {}", INDEX_FILE_NAME, error);
+                Index::default()
+            })
+    }
+
+    /// Saves this index in the output directory [dir]
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), String> {
+        let content = ::serde_json::to_string_pretty(self)
+            .map_err(|error| format!("could not serialize index: {}", error))?;
+        write_atomically(&dir.as_ref().join(INDEX_FILE_NAME), |file| {
+            file.write_all(content.as_bytes())
+                .map_err(|error| error.to_string())
+        }).map_err(|error| format!("could not save {}: {}", INDEX_FILE_NAME, error))
+    }
+
+    /// Loads the index of the output directory [dir], changes it with [change] and saves it.
+    /// Other processes can't change the index in between, so none of their changes get lost.
+    pub fn update<P, F, R>(dir: P, change: F) -> Result<R, String>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut Index) -> R,
+    {
+        let dir = dir.as_ref();
+        let _lock = Lock::acquire(dir)?;
+        let mut index = Index::load(dir);
+        let result = change(&mut index);
+        index.save(dir)?;
+        Ok(result)
+    }
+
+    /// The reason why an image with the [hash] must not be set,
+    /// if it differs in no more than [threshold] bits from a recent or banned wallpaper
+    pub fn rejection(&self, hash: u64, threshold: u32) -> Option<&'static str> {
+        let similar = |other: &u64| distance(*other, hash) <= threshold;
+
+        if self.banned.iter().any(similar) {
+            Some("it has been banned")
+        } else if self.recent.iter().any(similar) {
+            Some("it has been set recently")
+        } else {
+            None
+        }
+    }
+
+    /// Remembers that a wallpaper with the [hash] has been set
+    pub fn remember(&mut self, hash: u64) {
+        self.recent.push(hash);
+        if self.recent.len() > RECENT_SIZE {
+            let excess = self.recent.len() - RECENT_SIZE;
+            self.recent.drain(..excess);
+        }
+    }
+}
+
+/// The lock file of the index, which is removed when dropped
+struct Lock {
+    file: PathBuf,
+}
+
+impl Lock {
+    /// Creates the lock file in the output directory [dir], waiting while another process holds it
+    fn acquire(dir: &Path) -> Result<Self, String> {
+        let file = dir.join(format!("{}.{}", INDEX_FILE_NAME, LOCK_EXTENSION));
+        let start = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&file) {
+                Ok(_) => return Ok(Lock { file }),
+                Err(ref error) if error.kind() == ErrorKind::AlreadyExists => {}
+                Err(error) => return Err(format!("could not lock {}: {}", INDEX_FILE_NAME, error)),
+            }
+
+            let stale = metadata(&file)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > Duration::from_secs(STALE_LOCK_SECS));
+            if stale {
+                warn!("Removing the stale lock of {}", INDEX_FILE_NAME);
+                remove_file(&file).ok();
+            } else if start.elapsed() > Duration::from_secs(LOCK_TIMEOUT_SECS) {
+                return Err(format!("could not lock {}: it is in use", INDEX_FILE_NAME));
+            } else {
+                sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        remove_file(&self.file).ok();
+    }
+}
+
+/// The difference hash (dHash) of the image [file], turned upright according to its
+/// EXIF [orientation]. Similar images have hashes which differ only in a few bits.
+pub fn hash_file(file: &Path, orientation: u32) -> Result<u64, String> {
+    let image = image::open(file).map_err(|error| format!("could not decode image: {}", error))?;
+    Ok(dhash(&derived::upright(image, orientation)))
+}
+
+fn dhash(image: &DynamicImage) -> u64 {
+    // every bit tells whether a pixel is darker than its right neighbour
+    let small = image.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// The amount of bits in which the hashes [a] and [b] differ
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Remembers that [wallpaper] has been set, so that it and its reposts aren't chosen again soon
+pub fn remember_set(config: &Configuration, wallpaper: &Wallpaper) {
+    if let Some(hash) = wallpaper.hash {
+        Index::update(&config.output_dir, |index| index.remember(hash))
+            .map_err(|error| warn!("{}", error))
+            .ok();
+    }
+}
+
+/// Bans the current wallpaper, so that it and its reposts are never chosen again
+pub fn ban_current(config: &Configuration) -> Result<String, String> {
+    let current = ::wallpaper_lib::get()
+        .map_err(|error| format!("Could not get current wallpaper: {}", error))?;
+    let current_name = Path::new(&current)
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default()
+        .to_owned();
+
+    // derived images are named after their original
+    Index::update(&config.output_dir, |index| {
+        let (hash, title) = index
+            .images
+            .iter()
+            .filter(|(name, _)| current_name.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, entry)| (entry.hash, entry.title.clone()))
+            .ok_or_else(|| format!("The current wallpaper {} is unknown", current))?;
+
+        index.banned.push(hash);
+        Ok(format!("Banned '{}'", title))
+    })?
+}
+
+/// Removes images from the output directory which are similar to a larger one,
+/// returns the removed files. Nothing is removed if [dry_run] is set.
+pub fn dedup(config: &Configuration, dry_run: bool) -> Result<Vec<PathBuf>, String> {
+    let dir = Path::new(&config.output_dir);
+    let known = Index::load(dir);
+
+    // the index is only locked while it is changed, not while the images are hashed
+    let mut hashed = Vec::new();
+    let mut images = Vec::new();
+    let entries =
+        read_dir(dir).map_err(|error| format!("Could not read {}: {}", dir.display(), error))?;
+    for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+        let name = match path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let extension = path.extension().and_then(OsStr::to_str);
+        let ignored = name == INDEX_FILE_NAME
+            || extension == Some(PARTIAL_EXTENSION)
+            || extension == Some(VALIDATOR_EXTENSION)
+            || extension == Some(LOCK_EXTENSION);
+        if !path.is_file() || ignored {
+            continue;
+        }
+
+        let hash = match known.images.get(&name).map(|entry| entry.hash) {
+            Some(hash) => hash,
+            None => {
+                let orientation = read(&path).map(|data| derived::orientation(&data)).unwrap_or(1);
+                match hash_file(&path, orientation) {
+                    Ok(hash) => {
+                        let entry = Entry {
+                            hash,
+                            title: name.clone(),
+                            url: None,
+                            colors: None,
+                        };
+                        hashed.push((name.clone(), entry));
+                        hash
+                    }
+                    Err(error) => {
+                        warn!("Skipping {}: {}", name, error);
+                        continue;
+                    }
+                }
+            }
+        };
+        let pixels = image::image_dimensions(&path)
+            .map(|(width, height)| u64::from(width) * u64::from(height))
+            .unwrap_or(0);
+        images.push((path, name, hash, pixels));
+    }
+
+    // the largest of similar images is kept
+    images.sort_by_key(|&(_, _, _, pixels)| Reverse(pixels));
+    let mut kept: Vec<u64> = Vec::new();
+    let mut removed = Vec::new();
+    for (path, name, hash, _) in images {
+        if !kept.iter().any(|&other| distance(other, hash) <= config.duplicate_threshold) {
+            kept.push(hash);
+            continue;
+        }
+
+        if !dry_run {
+            remove_file(&path)
+                .map_err(|error| format!("Could not remove {}: {}", path.display(), error))?;
+            derived::remove_all(&path);
+        }
+        removed.push((path, name));
+    }
+
+    if !dry_run {
+        Index::update(dir, |index| {
+            for (name, entry) in hashed {
+                index.images.entry(name).or_insert(entry);
+            }
+            for (_, name) in removed.iter() {
+                index.images.remove(name);
+            }
+        })?;
+    }
+    Ok(removed.into_iter().map(|(path, _)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Index, INDEX_FILE_NAME, LOCK_EXTENSION};
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::process;
+    use std::thread;
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = temp_dir().join(format!("heaven-on-earth-index-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+
+        let updates: Vec<_> = (0..8)
+            .map(|hash| {
+                let dir = dir.clone();
+                thread::spawn(move || Index::update(&dir, |index| index.banned.push(hash)))
+            }).collect();
+        for update in updates {
+            update.join().unwrap().unwrap();
+        }
+
+        let mut banned = Index::load(&dir).banned;
+        banned.sort();
+        assert_eq!(banned, (0..8).collect::<Vec<u64>>());
+        let lock = dir.join(format!("{}.{}", INDEX_FILE_NAME, LOCK_EXTENSION));
+        assert!(!lock.exists());
+
+        remove_dir_all(&dir).ok();
+    }
+}
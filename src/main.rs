@@ -1,63 +1,93 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+extern crate chrono;
 #[macro_use]
 extern crate clap;
+extern crate image;
 extern crate immeta;
 #[macro_use]
 extern crate log;
 extern crate meval;
 extern crate rand;
+extern crate regex;
 extern crate reqwest;
 #[macro_use]
 extern crate serde_derive;
+extern crate cron;
+extern crate ctrlc;
 extern crate dirs;
-extern crate job_scheduler;
+extern crate exif;
+extern crate serde;
 extern crate serde_json;
 extern crate simplelog;
 extern crate wallpaper as wallpaper_lib;
 
+use chrono::Local;
 use clap::{App, ArgMatches};
 use configuration::{Configuration, RUN_BY_DEFAULT};
-use job_scheduler::{Job, JobScheduler};
+use control::Command;
+use daemon::Daemon;
 use platform::{install, uninstall};
-use simplelog::{CombinedLogger, Config, LevelFilter, TermLogger, WriteLogger};
+use simplelog::{CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger, WriteLogger};
 use std::fs::File;
-use std::thread::sleep;
-use std::time::Duration;
 use wallpaper::Wallpaper;
 
+mod colors;
 mod configuration;
+mod control;
+mod daemon;
+mod derived;
+mod http;
+mod index;
+mod pattern;
 mod platform;
 mod reddit;
+mod rules;
+mod schedule;
+mod scheduler;
+mod selection;
+mod shutdown;
+mod solar;
 mod utils;
 mod wallpaper;
 
 fn main() {
-    let log_file = utils::install_dir().join("latest.log");
+    let yaml = load_yaml!("cli.yml");
+    let mut app = App::from_yaml(yaml);
+    let matches = app.clone().get_matches();
 
-    CombinedLogger::init(vec![
-        #[cfg(debug_assertions)]
-        TermLogger::new(LevelFilter::Info, Config::default()).unwrap(),
-        WriteLogger::new(
+    let command = matches
+        .subcommand_name()
+        .and_then(Command::from_identifier);
+
+    let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
+    #[cfg(debug_assertions)]
+    loggers.push(TermLogger::new(LevelFilter::Info, Config::default()).unwrap());
+    // commands for a running instance must not replace its log file,
+    // and neither must another instance started while it is running
+    let replaces_log = match matches.subcommand_name() {
+        Some("ban") | Some("dedup") => false,
+        _ => command.is_none() && !control::is_running(),
+    };
+    if replaces_log {
+        let log_file = utils::install_dir().join("latest.log");
+        loggers.push(WriteLogger::new(
             LevelFilter::Info,
             Config::default(),
             File::create(log_file).unwrap(),
-        ),
-    ]).unwrap();
-
-    let yaml = load_yaml!("cli.yml");
-    let mut app = App::from_yaml(yaml);
-    let matches = app.clone().get_matches();
+        ));
+    }
+    CombinedLogger::init(loggers).unwrap();
 
     fn load_config<F>(matches: Option<&ArgMatches>, after: F)
     where
-        F: Fn(Configuration) -> (),
+        F: Fn(Configuration, &ArgMatches),
     {
         let matches = matches.map(ToOwned::to_owned).unwrap_or_default();
         match Configuration::init(&matches) {
             Ok(config) => {
                 info!("{:?}", config);
-                after(config)
+                after(config, &matches)
             }
             Err(error) => {
                 error!("{}", error);
@@ -65,10 +95,71 @@ fn main() {
         };
     }
 
+    fn print_response(response: Result<String, String>) {
+        match response {
+            Ok(response) => println!("{}", response),
+            Err(error) => eprintln!("{}", error),
+        }
+    }
+
     match matches.subcommand() {
-        ("run", matches) => load_config(matches, |cfg| run(&cfg)),
+        ("run", matches) => load_config(matches, run),
+
+        ("next", matches) => match control::send(Command::Next) {
+            Some(response) => print_response(response),
+            None => load_config(matches, |cfg, _| {
+                shutdown::handle_signals();
+                run_once(&cfg);
+            }),
+        },
+
+        ("current", _) => match control::send(Command::Current) {
+            Some(response) => print_response(response),
+            None => match ::wallpaper_lib::get() {
+                Ok(path) => println!("{}", path),
+                Err(e) => eprintln!("Could not get current wallpaper: {}", e),
+            },
+        },
+
+        ("previous", _)
+        | ("pause", _)
+        | ("resume", _)
+        | ("status", _)
+        | ("reload-config", _)
+        | ("quit", _) => match control::send(command.unwrap()) {
+            Some(response) => print_response(response),
+            None => eprintln!("heaven-on-earth is not running"),
+        },
+
+        ("ban", matches) => load_config(matches, |cfg, _| match index::ban_current(&cfg) {
+            Ok(message) => {
+                println!("{}", message);
+                match control::send(Command::Next) {
+                    Some(response) => print_response(response),
+                    None => {
+                        shutdown::handle_signals();
+                        run_once(&cfg);
+                    }
+                }
+            }
+            Err(error) => eprintln!("{}", error),
+        }),
+
+        ("dedup", matches) => load_config(matches, |cfg, matches| {
+            let dry_run = matches.is_present("dry-run");
+            match index::dedup(&cfg, dry_run) {
+                Ok(removed) => {
+                    for file in removed.iter() {
+                        println!("{}", file.display());
+                    }
+                    let verb = if dry_run { "Would remove" } else { "Removed" };
+                    println!("{} {} duplicates", verb, removed.len());
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        }),
 
-        ("install", matches) => load_config(matches, |cfg| match install(&cfg) {
+        ("install", matches) => load_config(matches, |cfg, _| match install(&cfg) {
             Ok(()) => info!("Installation succeeded!"),
             Err(e) => error!("Installation failed: {}", e),
         }),
@@ -80,41 +171,48 @@ fn main() {
 
         (_, matches) => if ::utils::install_dir().join(RUN_BY_DEFAULT).is_file() {
             info!("file '{}' found", RUN_BY_DEFAULT);
-            load_config(matches, |cfg| run(&cfg))
+            load_config(matches, run)
         } else {
             app.print_help().unwrap();
         },
     }
 }
 
-fn run(config: &Configuration) {
-    fn run_once(config: &Configuration) {
-        info!("Searching for a new wallpaper...");
-        match Wallpaper::find(config) {
-            Some(wallpaper) => match wallpaper.set() {
-                Ok(_) => (),
-                Err(err) => error!("Could not set wallpaper: {}", err),
-            },
-            None => error!("No wallpaper found!"),
-        };
-    }
-
-    fn run_repeating(config: &Configuration, cron_expr: &String) {
-        run_once(config);
+fn run(config: Configuration, matches: &ArgMatches) {
+    shutdown::handle_signals();
 
-        let job = Job::new(cron_expr.parse().unwrap(), || run_once(config));
-
-        let mut scheduler = JobScheduler::new();
-        scheduler.add(job);
-
-        loop {
-            scheduler.tick();
-            sleep(Duration::from_millis(500));
-        }
+    if config.run_every.is_some() || !config.run_at.is_empty() {
+        Daemon::new(config, matches).run()
+    } else {
+        run_once(&config);
     }
+}
 
-    match config.run_every {
-        Some(ref cron_expr) => run_repeating(config, cron_expr),
-        None => run_once(config),
+/// Searches for a new wallpaper and sets it, returns it if successful
+fn run_once(config: &Configuration) -> Option<Wallpaper> {
+    let config = match config.at(&Local::now()) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Could not apply rules: {}", err);
+            return None;
+        }
+    };
+
+    info!("Searching for a new wallpaper...");
+    match Wallpaper::find(&config) {
+        Some(wallpaper) => match wallpaper.set() {
+            Ok(_) => {
+                index::remember_set(&config, &wallpaper);
+                Some(wallpaper)
+            }
+            Err(err) => {
+                error!("Could not set wallpaper: {}", err);
+                None
+            }
+        },
+        None => {
+            error!("No wallpaper found!");
+            None
+        }
     }
 }
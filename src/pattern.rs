@@ -0,0 +1,66 @@
+use regex::{escape, Regex};
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+
+/// A case-insensitive keyword or, if enclosed in slashes, a regular expression
+/// which is searched for in titles
+#[derive(Clone)]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    /// 'true' if this pattern is found in [text]
+    pub fn matches(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    /// Parses a keyword like "[OC] comparison" or a regular expression like "/\bmaps?\b/"
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let expression = if source.len() > 1 && source.starts_with('/') && source.ends_with('/') {
+            source[1..source.len() - 1].to_owned()
+        } else {
+            escape(source)
+        };
+
+        let regex = Regex::new(&format!("(?i){}", expression))
+            .map_err(|error| format!("Invalid pattern '{}': {}", source, error))?;
+
+        Ok(Pattern {
+            source: source.to_owned(),
+            regex,
+        })
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Debug for Pattern {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Pattern({:?})", self.source)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        source.parse().map_err(D::Error::custom)
+    }
+}
@@ -2,6 +2,9 @@ use configuration::Configuration;
 use std::error::Error;
 use std::process::Command;
 
+/// The image formats which can be set as a wallpaper without converting them
+pub const DISPLAY_FORMATS: [&str; 3] = ["jpeg", "png", "gif"];
+
 pub fn install(config: &Configuration) -> Result<(), String> {
     Err("Your platform is not supported".to_owned())
 }
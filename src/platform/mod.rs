@@ -1,12 +1,18 @@
 #[cfg(target_os = "macos")]
+pub use self::darwin::DISPLAY_FORMATS;
+#[cfg(target_os = "macos")]
 pub use self::darwin::install;
 #[cfg(target_os = "macos")]
 pub use self::darwin::uninstall;
 #[cfg(target_os = "linux")]
+pub use self::linux::DISPLAY_FORMATS;
+#[cfg(target_os = "linux")]
 pub use self::linux::install;
 #[cfg(target_os = "linux")]
 pub use self::linux::uninstall;
 #[cfg(target_os = "windows")]
+pub use self::windows::DISPLAY_FORMATS;
+#[cfg(target_os = "windows")]
 pub use self::windows::install;
 #[cfg(target_os = "windows")]
 pub use self::windows::uninstall;
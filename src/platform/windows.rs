@@ -1,17 +1,19 @@
-use configuration::{Configuration, CONFIG_FILE_NAME, INSTALL_DIR, RUN_BY_DEFAULT};
+use configuration::{
+    ConfigFile, Configuration, Settings, CONFIG_FILE_NAME, INSTALL_DIR, RUN_BY_DEFAULT,
+};
 use std::env::current_exe;
 use std::fs::{copy, create_dir_all, remove_dir_all, remove_file, write, File};
 use std::path::PathBuf;
 use utils::{current_exe_name, home_dir};
 
+/// The image formats which can be set as a wallpaper without converting them
+pub const DISPLAY_FORMATS: [&str; 5] = ["jpeg", "png", "gif", "bmp", "tiff"];
+
 pub fn install(config: &Configuration) -> Result<(), String> {
     let home_dir = home_dir()?;
     let startup_dir = get_startup_dir(&home_dir);
     let install_dir = home_dir.join(INSTALL_DIR);
 
-    let mut config = (*config).clone();
-    config.output_dir = install_dir.join("out").to_string_lossy().into_owned();
-
     info!("Copying executable to {:?}..", startup_dir);
     let current_executable =
         current_exe().map_err(|e| format!("Could not find current executable: {}", e))?;
@@ -22,9 +24,20 @@ pub fn install(config: &Configuration) -> Result<(), String> {
     create_dir_all(&install_dir).unwrap();
 
     info!("Creating configuration file..");
-    let config_str = ::serde_json::to_string_pretty(&config)
-        .map_err(|e| format!("Could not serialize configuration: {}", e))?;
     let config_file = install_dir.join(CONFIG_FILE_NAME);
+    let mut file = if config_file.is_file() {
+        ConfigFile::load_from_file(&config_file)?
+    } else {
+        ConfigFile::default()
+    };
+    // the command-line arguments are kept for the runs on log-in, the profiles stay as they are
+    let mut layers = vec![file.settings];
+    layers.extend(config.command_line.clone());
+    file.settings = Settings::combine(layers)?;
+    file.settings.output_dir = Some(install_dir.join("out").to_string_lossy().into_owned());
+
+    let config_str = ::serde_json::to_string_pretty(&file)
+        .map_err(|e| format!("Could not serialize configuration: {}", e))?;
     write(&config_file, config_str)
         .map_err(|e| format!("Could not create configuration file: {}", e))?;
 
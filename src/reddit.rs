@@ -1,5 +1,5 @@
 use configuration::Configuration;
-use rand::{thread_rng, Rng};
+use rand::Rng;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 
 #[allow(dead_code)]
@@ -23,17 +23,19 @@ pub enum Mode {
     Top(Span),
 }
 
-pub fn create_url(config: &Configuration) -> String {
+/// The URL of the listing of a subreddit of [config], which is chosen using [rng]
+pub fn create_url<R: Rng>(config: &Configuration, rng: &mut R) -> String {
     use reddit::Mode::*;
 
     let mut subreddits = config.subreddits.clone();
-    thread_rng().shuffle(&mut subreddits);
+    rng.shuffle(&mut subreddits);
 
     let subreddit = subreddits.first().unwrap();
     info!("Searching on /r/{}...", subreddit);
 
     let mut url = format!(
-        "https://www.reddit.com/r/{}/{}.json?limit={}",
+        "{}/r/{}/{}.json?limit={}",
+        config.reddit_url.trim_end_matches('/'),
         subreddit,
         config.mode.identifier(),
         config.query_size
@@ -0,0 +1,176 @@
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use configuration::Settings;
+use solar::SolarEvent;
+
+/// Settings which override the configuration on certain weekdays and/or during a time range
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Rule {
+    /// the weekdays ("mon", "tuesday", ...) on which this rule applies or [None] for every day
+    pub days: Option<Vec<String>>,
+    /// the local time ("HH:MM") or solar event ("sunset", "civil_dusk", ...)
+    /// from which on this rule applies
+    pub from: Option<String>,
+    /// the local time ("HH:MM") or solar event until which this rule applies,
+    /// may be before [from] for ranges spanning midnight
+    pub to: Option<String>,
+    /// the name of a profile which is layered on top of the configuration while this rule applies
+    pub profile: Option<String>,
+    /// the settings which are layered on top of the configuration
+    /// (and [profile]) while this rule applies
+    pub settings: Option<Settings>,
+}
+
+/// A time of the day, either fixed or depending on the position of the sun
+enum TimeOfDay {
+    Clock(NaiveTime),
+    Solar(SolarEvent),
+}
+
+impl Rule {
+    /// Checks that the days and times of this rule can be parsed, that a [location] is known
+    /// if they depend on the sun and that its settings don't change the schedule
+    pub fn validate(&self, location: Option<(f32, f32)>) -> Result<(), String> {
+        if let Some(ref settings) = self.settings {
+            let scheduling = [
+                ("run_every", settings.run_every.is_some()),
+                ("run_at", settings.run_at.is_some()),
+                ("jitter", settings.jitter.is_some()),
+            ];
+            if let Some((name, _)) = scheduling.iter().find(|(_, set)| *set) {
+                return Err(format!(
+                    "Rules can't change '{}', set it outside of rules and profiles used by them",
+                    name
+                ));
+            }
+        }
+
+        self.weekdays()?;
+        for time in [parse_time(&self.from)?, parse_time(&self.to)?].iter() {
+            if let Some(TimeOfDay::Solar(event)) = time {
+                if location.is_none() {
+                    return Err(format!(
+                        "Rule uses '{}', but 'latitude' and 'longitude' are not set",
+                        event
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 'true' if this rule applies at [time], else 'false'
+    pub fn applies_at(&self, time: &DateTime<Local>, location: Option<(f32, f32)>) -> bool {
+        let on_day = match self.weekdays() {
+            Ok(Some(days)) => days.contains(&time.weekday()),
+            Ok(None) => true,
+            Err(_) => false,
+        };
+
+        let resolve = |time_of_day: &Option<String>| -> Result<Option<NaiveTime>, ()> {
+            match parse_time(time_of_day).map_err(|_| ())? {
+                Some(TimeOfDay::Clock(clock)) => Ok(Some(clock)),
+                Some(TimeOfDay::Solar(event)) => {
+                    let (latitude, longitude) = location.ok_or(())?;
+                    event
+                        .time_on(time.date().naive_local(), latitude, longitude)
+                        .map(|utc| Some(utc.with_timezone(&Local).time()))
+                        .ok_or(())
+                }
+                None => Ok(None),
+            }
+        };
+
+        let now = time.time();
+        let in_range = match (resolve(&self.from), resolve(&self.to)) {
+            (Ok(Some(from)), Ok(Some(to))) if from <= to => from <= now && now < to,
+            (Ok(Some(from)), Ok(Some(to))) => from <= now || now < to,
+            (Ok(Some(from)), Ok(None)) => from <= now,
+            (Ok(None), Ok(Some(to))) => now < to,
+            (Ok(None), Ok(None)) => true,
+            _ => false,
+        };
+
+        on_day && in_range
+    }
+
+    fn weekdays(&self) -> Result<Option<Vec<Weekday>>, String> {
+        match self.days {
+            Some(ref days) => days
+                .iter()
+                .map(|day| {
+                    day.parse::<Weekday>()
+                        .map_err(|_| format!("Invalid weekday '{}' in rule", day))
+                }).collect::<Result<Vec<_>, _>>()
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse_time(time: &Option<String>) -> Result<Option<TimeOfDay>, String> {
+    match time {
+        Some(time) => match SolarEvent::from_identifier(time) {
+            Some(event) => Ok(Some(TimeOfDay::Solar(event))),
+            None => NaiveTime::parse_from_str(time, "%H:%M")
+                .map(|clock| Some(TimeOfDay::Clock(clock)))
+                .map_err(|error| format!("Invalid time '{}' in rule: {}", time, error)),
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rule;
+    use chrono::{Local, TimeZone};
+
+    fn rule(json: &str) -> Rule {
+        ::serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn time_range_may_span_midnight() {
+        let night = rule(r#"{"from": "22:00", "to": "06:00"}"#);
+        // 2024-06-21 is a friday
+        let at = |hour, minute| Local.ymd(2024, 6, 21).and_hms(hour, minute, 0);
+
+        assert!(night.applies_at(&at(23, 30), None));
+        assert!(night.applies_at(&at(5, 59), None));
+        assert!(night.applies_at(&at(22, 0), None));
+        assert!(!night.applies_at(&at(6, 0), None));
+        assert!(!night.applies_at(&at(12, 0), None));
+    }
+
+    #[test]
+    fn time_range_within_a_day() {
+        let office = rule(r#"{"from": "09:00", "to": "17:00"}"#);
+        let at = |hour| Local.ymd(2024, 6, 21).and_hms(hour, 0, 0);
+
+        assert!(office.applies_at(&at(9), None));
+        assert!(!office.applies_at(&at(17), None));
+        assert!(!office.applies_at(&at(20), None));
+    }
+
+    #[test]
+    fn weekdays_restrict_the_rule() {
+        let weekend = rule(r#"{"days": ["sat", "sunday"]}"#);
+
+        assert!(weekend.applies_at(&Local.ymd(2024, 6, 22).and_hms(12, 0, 0), None));
+        assert!(!weekend.applies_at(&Local.ymd(2024, 6, 21).and_hms(12, 0, 0), None));
+    }
+
+    #[test]
+    fn solar_events_require_a_location() {
+        let evening = rule(r#"{"from": "sunset"}"#);
+
+        assert!(evening.validate(None).is_err());
+        assert!(evening.validate(Some((52.52, 13.405))).is_ok());
+        assert!(!evening.applies_at(&Local.ymd(2024, 6, 21).and_hms(23, 0, 0), None));
+    }
+
+    #[test]
+    fn invalid_times_and_days_are_rejected() {
+        assert!(rule(r#"{"from": "25:00"}"#).validate(None).is_err());
+        assert!(rule(r#"{"days": ["someday"]}"#).validate(None).is_err());
+    }
+}
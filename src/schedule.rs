@@ -0,0 +1,138 @@
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule as CronSchedule;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+use std::sync::Arc;
+use utils::parse_duration;
+
+/// When to search for a new wallpaper, either a cron expression or a fixed interval
+#[derive(Clone)]
+pub struct Schedule {
+    expression: String,
+    kind: Kind,
+}
+
+#[derive(Clone)]
+enum Kind {
+    Cron(Arc<CronSchedule>),
+    Interval(Duration),
+}
+
+impl Schedule {
+    /// The first point in time after [time] at which this schedule fires
+    pub fn next_after(&self, time: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self.kind {
+            Kind::Cron(ref cron) => cron.after(time).next(),
+            Kind::Interval(interval) => time.checked_add_signed(interval),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Parses an interval ("30m", "2h", "1d"), a cron expression with 5, 6 or 7 fields
+    /// or one of the aliases "@hourly", "@daily", "@weekly", "@monthly" and "@yearly"
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expression = expression.trim();
+
+        // cron expressions have several fields and aliases start with '@'
+        let is_interval = !expression.starts_with('@') && !expression.contains(char::is_whitespace);
+
+        let kind = match parse_duration(expression) {
+            Ok(interval) => Kind::Interval(interval),
+            Err(error) if is_interval => return Err(error),
+            Err(_) => {
+                // 5 fields are a classic crontab line without the leading seconds
+                let cron = if expression.split_whitespace().count() == 5 {
+                    format!("0 {}", expression)
+                } else {
+                    expression.to_owned()
+                };
+                let cron = cron
+                    .parse::<CronSchedule>()
+                    .map_err(|error| format!("Invalid schedule '{}': {}", expression, error))?;
+                Kind::Cron(Arc::new(cron))
+            }
+        };
+
+        Ok(Schedule {
+            expression: expression.to_owned(),
+            kind,
+        })
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl Debug for Schedule {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Schedule({:?})", self.expression)
+    }
+}
+
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.expression)
+    }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let expression = String::deserialize(deserializer)?;
+        expression.parse().map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Schedule;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn intervals_fire_after_the_given_duration() {
+        let schedule = "90m".parse::<Schedule>().unwrap();
+        let time = Utc.ymd(2024, 6, 21).and_hms(10, 15, 0);
+
+        assert_eq!(
+            schedule.next_after(&time),
+            Some(Utc.ymd(2024, 6, 21).and_hms(11, 45, 0))
+        );
+    }
+
+    #[test]
+    fn cron_expressions_may_omit_the_seconds() {
+        let schedule = "30 * * * *".parse::<Schedule>().unwrap();
+        let time = Utc.ymd(2024, 6, 21).and_hms(10, 15, 0);
+
+        assert_eq!(
+            schedule.next_after(&time),
+            Some(Utc.ymd(2024, 6, 21).and_hms(10, 30, 0))
+        );
+    }
+
+    #[test]
+    fn aliases_are_supported() {
+        let schedule = "@hourly".parse::<Schedule>().unwrap();
+        let time = Utc.ymd(2024, 6, 21).and_hms(10, 15, 0);
+
+        assert_eq!(
+            schedule.next_after(&time),
+            Some(Utc.ymd(2024, 6, 21).and_hms(11, 0, 0))
+        );
+        assert_eq!(schedule.to_string(), "@hourly");
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!("61 * * * *".parse::<Schedule>().is_err());
+        assert!("every hour".parse::<Schedule>().is_err());
+        assert!("9999999999d".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use chrono::{DateTime, Duration, Utc};
+use configuration::Configuration;
+use rand::{thread_rng, Rng};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Ticks longer apart than this mean that the process was suspended
+const SUSPEND_THRESHOLD_SECS: i64 = 30;
+
+/// Decides when to search for a new wallpaper while running repeatedly
+pub struct Scheduler {
+    last_tick: DateTime<Utc>,
+    /// the next slot of [Configuration::run_every]
+    next_slot: Option<DateTime<Utc>>,
+    /// the point in time at which a due run starts, after the jitter has passed
+    pending: Option<DateTime<Utc>>,
+}
+
+impl Scheduler {
+    pub fn new(config: &Configuration) -> Self {
+        let now = Utc::now();
+        Scheduler {
+            last_tick: now,
+            next_slot: next_slot(config, &now),
+            pending: None,
+        }
+    }
+
+    /// Advances this scheduler to [now], returns 'true' if a run should start now.
+    /// Slots which were missed while the process was suspended result in a single run.
+    pub fn tick(&mut self, config: &Configuration, now: DateTime<Utc>) -> bool {
+        let gap = now.signed_duration_since(self.last_tick);
+        if gap > Duration::seconds(SUSPEND_THRESHOLD_SECS) {
+            info!("Resumed after {} minutes", gap.num_minutes());
+        }
+
+        let mut due = false;
+        if self.next_slot.map(|slot| slot <= now).unwrap_or(false) {
+            due = true;
+            self.next_slot = next_slot(config, &now);
+        }
+        if let Some((latitude, longitude)) = config.location() {
+            let last_tick = self.last_tick;
+            let solar_event = config
+                .run_at
+                .iter()
+                .find(|event| event.happens_between(&last_tick, &now, latitude, longitude));
+            if let Some(event) = solar_event {
+                info!("Reached {}", event);
+                due = true;
+            }
+        }
+        self.last_tick = now;
+
+        if due && self.pending.is_none() {
+            let delay = jitter(config);
+            if delay > Duration::zero() {
+                info!("Delaying search by {} seconds", delay.num_seconds());
+            }
+            self.pending = Some(now + delay);
+        }
+
+        match self.pending {
+            Some(start) if start <= now => {
+                self.pending = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Recomputes the next slot after the schedule of [config] has changed at [now]
+    pub fn reschedule(&mut self, config: &Configuration, now: DateTime<Utc>) {
+        self.next_slot = next_slot(config, &now);
+    }
+
+    /// The point in time of the next scheduled run, not including solar events
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        self.pending.or(self.next_slot)
+    }
+}
+
+/// Ensures that searches never overlap
+#[derive(Clone, Default)]
+pub struct SingleFlight {
+    running: Arc<AtomicBool>,
+}
+
+impl SingleFlight {
+    /// Runs [job] on a new thread, returns 'false' without running it
+    /// if the previous job is still running
+    pub fn spawn<F>(&self, job: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        let done = Done(self.running.clone());
+        thread::spawn(move || {
+            let _done = done;
+            job()
+        });
+        true
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Marks the job as finished when dropped, even if it panicked
+struct Done(Arc<AtomicBool>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+fn next_slot(config: &Configuration, after: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+    config
+        .run_every
+        .as_ref()
+        .and_then(|schedule| schedule.next_after(after))
+}
+
+/// A random delay between zero and [Configuration::jitter]
+fn jitter(config: &Configuration) -> Duration {
+    let max = config.jitter.map(|jitter| jitter.num_seconds()).unwrap_or(0);
+
+    if max > 0 {
+        Duration::seconds(thread_rng().gen_range(0, max))
+    } else {
+        Duration::zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, SingleFlight};
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use configuration::{Configuration, Settings};
+    use reddit::Mode;
+    use std::sync::mpsc::channel;
+    use std::thread::sleep;
+    use std::time::Duration as StdDuration;
+
+    fn config(jitter: Option<&str>) -> Configuration {
+        Settings {
+            mode: Some(Mode::Hot),
+            run_every: Some("0 * * * *".to_owned()),
+            jitter: jitter.map(str::to_owned),
+            ..Settings::default()
+        }.into_config()
+        .unwrap()
+    }
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.ymd(2024, 6, 21).and_hms(hour, minute, second)
+    }
+
+    fn scheduler(config: &Configuration, start: DateTime<Utc>) -> Scheduler {
+        Scheduler {
+            last_tick: start,
+            next_slot: super::next_slot(config, &start),
+            pending: None,
+        }
+    }
+
+    #[test]
+    fn missed_slots_result_in_a_single_run() {
+        let config = config(None);
+        let mut scheduler = scheduler(&config, at(10, 15, 0));
+
+        assert!(!scheduler.tick(&config, at(10, 59, 59)));
+        assert!(scheduler.tick(&config, at(11, 0, 0)));
+        // suspended from 11:00 until 14:20, missing the slots at 12, 13 and 14 o'clock
+        assert!(scheduler.tick(&config, at(14, 20, 0)));
+        assert!(!scheduler.tick(&config, at(14, 20, 1)));
+        assert!(!scheduler.tick(&config, at(14, 59, 59)));
+        assert!(scheduler.tick(&config, at(15, 0, 0)));
+    }
+
+    #[test]
+    fn jitter_delays_runs_within_its_limit() {
+        let config = config(Some("10m"));
+        for _ in 0..20 {
+            let mut scheduler = scheduler(&config, at(10, 59, 0));
+
+            let runs: Vec<i64> = (0..20 * 60)
+                .map(|second| at(11, 0, 0) + Duration::seconds(second))
+                .filter(|&time| scheduler.tick(&config, time))
+                .map(|time| (time - at(11, 0, 0)).num_seconds())
+                .collect();
+
+            assert_eq!(runs.len(), 1);
+            assert!(runs[0] < 10 * 60, "started {} seconds late", runs[0]);
+        }
+    }
+
+    #[test]
+    fn single_flight_allows_one_job_at_a_time() {
+        let single_flight = SingleFlight::default();
+        let (finish, finished) = channel::<()>();
+
+        assert!(single_flight.spawn(move || finished.recv().unwrap()));
+        assert!(single_flight.is_running());
+        assert!(!single_flight.spawn(|| {}));
+
+        finish.send(()).unwrap();
+        wait_until_idle(&single_flight);
+        assert!(single_flight.spawn(|| {}));
+    }
+
+    #[test]
+    fn single_flight_is_released_when_a_job_panics() {
+        let single_flight = SingleFlight::default();
+
+        assert!(single_flight.spawn(|| panic!("failed search")));
+        wait_until_idle(&single_flight);
+        assert!(single_flight.spawn(|| {}));
+    }
+
+    fn wait_until_idle(single_flight: &SingleFlight) {
+        for _ in 0..100 {
+            if !single_flight.is_running() {
+                return;
+            }
+            sleep(StdDuration::from_millis(10));
+        }
+        panic!("the job is still running");
+    }
+}
@@ -0,0 +1,157 @@
+use chrono::{DateTime, Utc};
+use rand::prng::ChaChaRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+use std::cmp::Ordering;
+use std::f64::consts::LN_2;
+use wallpaper::Wallpaper;
+
+/// The age in hours after which posts are half as likely when weighting by recency
+const HALF_LIFE_HOURS: f64 = 24.0;
+
+/// What the chance of a wallpaper to be chosen depends on
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weighting {
+    /// the chance is proportional to the score of the post
+    Score,
+    /// the chance halves with every day the post is old
+    Recency,
+    /// the chance depends on both the score and the age of the post
+    Combined,
+}
+
+impl Weighting {
+    pub fn from_identifier(id: &str) -> Option<Self> {
+        match &*id.to_lowercase() {
+            "score" => Some(Weighting::Score),
+            "recency" => Some(Weighting::Recency),
+            "combined" => Some(Weighting::Combined),
+            _ => None,
+        }
+    }
+
+    /// The natural logarithm of the weight of [wallpaper], whose age is measured at [reference]
+    fn log_weight(self, wallpaper: &Wallpaper, reference: Option<DateTime<Utc>>) -> f64 {
+        let score = (wallpaper.score.max(0) as f64 + 1.0).ln();
+        let hours = match (reference, wallpaper.created) {
+            (Some(reference), Some(created)) => {
+                (reference - created).num_minutes().max(0) as f64 / 60.0
+            }
+            _ => 0.0,
+        };
+        let recency = -hours / HALF_LIFE_HOURS * LN_2;
+
+        match self {
+            Weighting::Score => score,
+            Weighting::Recency => recency,
+            Weighting::Combined => score + recency,
+        }
+    }
+}
+
+/// A random number generator which produces the same numbers every time for the same [seed],
+/// or different ones if there is none
+pub fn rng(seed: Option<u64>) -> ChaChaRng {
+    match seed {
+        Some(seed) => {
+            let mut bytes = [0; 32];
+            bytes[..8].copy_from_slice(&seed.to_le_bytes());
+            ChaChaRng::from_seed(bytes)
+        }
+        None => ChaChaRng::from_entropy(),
+    }
+}
+
+/// Shuffles [wallpapers] so that every one of them comes before the remaining ones with
+/// a chance proportional to its weight raised to the power of 1 / [temperature].
+/// Lower temperatures favor heavy wallpapers, higher ones approach a uniform shuffle.
+pub fn weighted_shuffle<R: Rng>(
+    wallpapers: &mut Vec<Wallpaper>,
+    weighting: Weighting,
+    temperature: f32,
+    rng: &mut R,
+) {
+    // ages are measured from the newest post instead of the current time,
+    // so that the same listing and seed always result in the same order
+    let newest = wallpapers.iter().filter_map(|wallpaper| wallpaper.created).max();
+
+    // sorting by the logarithms of the weights plus Gumbel noise is
+    // the same as drawing the wallpapers one by one without replacement
+    let mut keyed: Vec<(f64, Wallpaper)> = wallpapers
+        .drain(..)
+        .map(|wallpaper| {
+            let gumbel = -(-rng.gen::<f64>().ln()).ln();
+            let key = weighting.log_weight(&wallpaper, newest) / f64::from(temperature) + gumbel;
+            (key, wallpaper)
+        }).collect();
+
+    keyed.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    wallpapers.extend(keyed.into_iter().map(|(_, wallpaper)| wallpaper));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rng, weighted_shuffle, Weighting};
+    use configuration::{Configuration, Settings};
+    use rand::Rng;
+    use reddit::{self, Mode};
+    use wallpaper::Wallpaper;
+
+    fn config() -> Configuration {
+        let subreddits = ["EarthPorn", "SkyPorn", "wallpapers", "art", "CityPorn", "spaceporn"];
+        Settings {
+            mode: Some(Mode::Hot),
+            subreddits: Some(subreddits.iter().map(|name| name.to_string()).collect()),
+            ..Settings::default()
+        }.into_config()
+        .unwrap()
+    }
+
+    fn posts() -> Vec<Wallpaper> {
+        (0..20)
+            .map(|i| {
+                let post = ::serde_json::json!({
+                    "title": format!("Post {}", i),
+                    "url": format!("https://i.example.com/{}.jpg", i),
+                    "score": i * 37 % 500,
+                    "created_utc": 1_700_000_000.0 - f64::from(i) * 3_600.0,
+                });
+                Wallpaper::from_json(&post).unwrap()
+            }).collect()
+    }
+
+    fn titles(wallpapers: &[Wallpaper]) -> Vec<String> {
+        wallpapers.iter().map(|wallpaper| wallpaper.title.clone()).collect()
+    }
+
+    /// The subreddit, the shuffled and the weighted order chosen with [seed]
+    fn choices(seed: u64) -> (String, Vec<String>, Vec<String>) {
+        let mut rng = rng(Some(seed));
+        let url = reddit::create_url(&config(), &mut rng);
+
+        let mut shuffled = posts();
+        rng.shuffle(&mut shuffled);
+
+        let mut weighted = posts();
+        weighted_shuffle(&mut weighted, Weighting::Combined, 1.0, &mut rng);
+
+        (url, titles(&shuffled), titles(&weighted))
+    }
+
+    #[test]
+    fn the_same_seed_makes_the_same_choices() {
+        assert_eq!(choices(42), choices(42));
+        assert_ne!(choices(42), choices(43));
+    }
+
+    #[test]
+    fn heavier_wallpapers_come_first_at_low_temperatures() {
+        let mut wallpapers = posts();
+        weighted_shuffle(&mut wallpapers, Weighting::Score, 0.01, &mut rng(Some(1)));
+
+        let scores: Vec<i64> = wallpapers.iter().map(|wallpaper| wallpaper.score).collect();
+        let mut sorted = scores.clone();
+        sorted.sort_by(|a, b| b.cmp(a));
+        assert_eq!(scores, sorted);
+    }
+}
@@ -0,0 +1,26 @@
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Requests a graceful shutdown on SIGINT and SIGTERM (Ctrl-C on Windows).
+/// A second signal exits immediately.
+pub fn handle_signals() {
+    ::ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            exit(130);
+        }
+        info!("Shutting down, send the signal again to force it...");
+    }).map_err(|error| warn!("Could not install signal handler: {}", error))
+    .ok();
+}
+
+/// Requests a graceful shutdown like a signal does, e.g. on the command 'quit'
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// 'true' if the current job should be aborted because the process is shutting down
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
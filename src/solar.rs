@@ -0,0 +1,203 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The julian date of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.0;
+/// The julian date of 1970-01-01 00:00 UTC
+const UNIX_EPOCH: f64 = 2_440_587.5;
+
+/// A point in time during the day which depends on the position of the sun
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SolarEvent {
+    AstronomicalDawn,
+    NauticalDawn,
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+    NauticalDusk,
+    AstronomicalDusk,
+}
+
+impl SolarEvent {
+    pub fn from_identifier(id: &str) -> Option<Self> {
+        use solar::SolarEvent::*;
+        match &*id.to_lowercase().replace('-', "_") {
+            "astronomical_dawn" => Some(AstronomicalDawn),
+            "nautical_dawn" => Some(NauticalDawn),
+            "civil_dawn" | "dawn" => Some(CivilDawn),
+            "sunrise" => Some(Sunrise),
+            "sunset" => Some(Sunset),
+            "civil_dusk" | "dusk" => Some(CivilDusk),
+            "nautical_dusk" => Some(NauticalDusk),
+            "astronomical_dusk" => Some(AstronomicalDusk),
+            _ => None,
+        }
+    }
+
+    /// The time of this event on [date] at the given coordinates
+    /// or [None] if the sun does not reach the required elevation that day
+    pub fn time_on(self, date: NaiveDate, latitude: f32, longitude: f32) -> Option<DateTime<Utc>> {
+        let latitude = f64::from(latitude).to_radians();
+        let longitude = f64::from(longitude);
+
+        // days between J2000 and noon of [date], corrected to mean solar noon
+        let noon = date.and_hms(12, 0, 0);
+        let days = (noon.timestamp() as f64 / 86_400.0 + UNIX_EPOCH - J2000).round();
+        let mean_noon = days - longitude / 360.0;
+
+        let anomaly = (357.5291 + 0.985_600_28 * mean_noon) % 360.0;
+        let anomaly_rad = anomaly.to_radians();
+        let center = 1.9148 * anomaly_rad.sin()
+            + 0.0200 * (2.0 * anomaly_rad).sin()
+            + 0.0003 * (3.0 * anomaly_rad).sin();
+        let ecliptic_longitude = ((anomaly + center + 180.0 + 102.9372) % 360.0).to_radians();
+
+        let transit = J2000 + mean_noon + 0.0053 * anomaly_rad.sin()
+            - 0.0069 * (2.0 * ecliptic_longitude).sin();
+        let declination = (ecliptic_longitude.sin() * 23.4397f64.to_radians().sin()).asin();
+
+        let hour_angle_cos = (self.elevation().to_radians().sin()
+            - latitude.sin() * declination.sin())
+            / (latitude.cos() * declination.cos());
+        if hour_angle_cos.abs() > 1.0 {
+            return None;
+        }
+        let hour_angle = hour_angle_cos.acos().to_degrees();
+
+        let julian = if self.is_morning() {
+            transit - hour_angle / 360.0
+        } else {
+            transit + hour_angle / 360.0
+        };
+
+        let millis = ((julian - UNIX_EPOCH) * 86_400_000.0).round() as i64;
+        Some(Utc.timestamp(0, 0) + Duration::milliseconds(millis))
+    }
+
+    /// 'true' if this event happens in the interval (from, to], else 'false'
+    pub fn happens_between(
+        self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+        latitude: f32,
+        longitude: f32,
+    ) -> bool {
+        // the event of a UTC date may fall onto the previous or the next day
+        let mut date = from.date().naive_utc().pred();
+        let last = to.date().naive_utc().succ();
+
+        while date <= last {
+            match self.time_on(date, latitude, longitude) {
+                Some(time) if *from < time && time <= *to => return true,
+                _ => date = date.succ(),
+            }
+        }
+        false
+    }
+
+    /// The elevation of the sun's center in degrees at this event
+    fn elevation(self) -> f64 {
+        use solar::SolarEvent::*;
+        match self {
+            AstronomicalDawn | AstronomicalDusk => -18.0,
+            NauticalDawn | NauticalDusk => -12.0,
+            CivilDawn | CivilDusk => -6.0,
+            // refraction and the radius of the sun's disc
+            Sunrise | Sunset => -0.833,
+        }
+    }
+
+    fn is_morning(self) -> bool {
+        use solar::SolarEvent::*;
+        match self {
+            AstronomicalDawn | NauticalDawn | CivilDawn | Sunrise => true,
+            Sunset | CivilDusk | NauticalDusk | AstronomicalDusk => false,
+        }
+    }
+}
+
+impl Display for SolarEvent {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        use solar::SolarEvent::*;
+
+        let to_str = match self {
+            AstronomicalDawn => "astronomical_dawn",
+            NauticalDawn => "nautical_dawn",
+            CivilDawn => "civil_dawn",
+            Sunrise => "sunrise",
+            Sunset => "sunset",
+            CivilDusk => "civil_dusk",
+            NauticalDusk => "nautical_dusk",
+            AstronomicalDusk => "astronomical_dusk",
+        };
+
+        write!(f, "{}", to_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SolarEvent;
+    use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+    const BERLIN: (f32, f32) = (52.52, 13.405);
+    const LOS_ANGELES: (f32, f32) = (34.05, -118.24);
+    const TROMSO: (f32, f32) = (69.65, 18.96);
+
+    fn midsummer() -> NaiveDate {
+        NaiveDate::from_ymd(2024, 6, 21)
+    }
+
+    /// Asserts that [actual] is within two minutes of [expected]
+    fn assert_close(actual: Option<DateTime<Utc>>, expected: DateTime<Utc>) {
+        let actual = actual.expect("event does not happen");
+        let difference = (actual - expected).num_seconds().abs();
+        assert!(difference <= 120, "{} is not close to {}", actual, expected);
+    }
+
+    #[test]
+    fn sunrise_and_sunset_in_berlin() {
+        let (latitude, longitude) = BERLIN;
+        assert_close(
+            SolarEvent::Sunrise.time_on(midsummer(), latitude, longitude),
+            Utc.ymd(2024, 6, 21).and_hms(2, 43, 0),
+        );
+        assert_close(
+            SolarEvent::Sunset.time_on(midsummer(), latitude, longitude),
+            Utc.ymd(2024, 6, 21).and_hms(19, 33, 0),
+        );
+    }
+
+    #[test]
+    fn sunset_in_los_angeles_falls_onto_the_next_utc_day() {
+        let (latitude, longitude) = LOS_ANGELES;
+        let sunset = SolarEvent::Sunset.time_on(midsummer(), latitude, longitude);
+        assert_eq!(sunset.unwrap().date(), Utc.ymd(2024, 6, 22));
+    }
+
+    #[test]
+    fn there_is_no_sunset_during_polar_day() {
+        let (latitude, longitude) = TROMSO;
+        assert_eq!(SolarEvent::Sunset.time_on(midsummer(), latitude, longitude), None);
+        assert_eq!(SolarEvent::Sunrise.time_on(midsummer(), latitude, longitude), None);
+    }
+
+    #[test]
+    fn happens_between_finds_the_event_of_the_interval() {
+        let (latitude, longitude) = BERLIN;
+        let at = |hour| Utc.ymd(2024, 6, 21).and_hms(hour, 0, 0);
+
+        assert!(SolarEvent::Sunset.happens_between(&at(19), &at(20), latitude, longitude));
+        assert!(!SolarEvent::Sunset.happens_between(&at(20), &at(21), latitude, longitude));
+        assert!(!SolarEvent::Sunrise.happens_between(&at(19), &at(20), latitude, longitude));
+    }
+
+    #[test]
+    fn parses_identifiers() {
+        assert_eq!(SolarEvent::from_identifier("civil-dusk"), Some(SolarEvent::CivilDusk));
+        assert_eq!(SolarEvent::from_identifier("Dawn"), Some(SolarEvent::CivilDawn));
+        assert_eq!(SolarEvent::from_identifier("noon"), None);
+    }
+}
@@ -1,4 +1,8 @@
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{remove_file, rename, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use wallpaper::PARTIAL_EXTENSION;
 
 pub fn current_exe_name() -> Result<String, String> {
     ::std::env::current_exe()
@@ -21,3 +25,196 @@ pub fn install_dir() -> PathBuf {
 
     dir
 }
+
+/// Writes the file [path] through [write]. The data goes to a temporary file next to it first,
+/// which only replaces [path] once it has been written completely.
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<(), String>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), String>,
+{
+    let mut partial_name = OsString::from(path.file_name().unwrap_or_default());
+    partial_name.push(".");
+    partial_name.push(PARTIAL_EXTENSION);
+    let partial_path = path.with_file_name(partial_name);
+
+    let written = File::create(&partial_path)
+        .map_err(|e| format!("could not create file: {}", e))
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            let file = writer
+                .into_inner()
+                .map_err(|e| format!("could not write to file: {}", e))?;
+            file.sync_all()
+                .map_err(|e| format!("could not write to file: {}", e))
+        }).and_then(|_| {
+            rename(&partial_path, path).map_err(|e| format!("could not rename file: {}", e))
+        });
+    if written.is_err() {
+        remove_file(&partial_path).ok();
+    }
+    written
+}
+
+/// The longest duration which is accepted, about 100 years
+const MAX_DURATION_SECS: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Parses a duration like "90s", "30m", "2h", "1d", "1w" or "1h30m"
+pub fn parse_duration(string: &str) -> Result<::chrono::Duration, String> {
+    use chrono::Duration;
+
+    let invalid = || format!("Invalid duration '{}'", string);
+
+    let mut total: i64 = 0;
+    let mut number = String::new();
+    for c in string.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let amount = number.parse::<i64>().map_err(|_| invalid())?;
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total = amount
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .filter(|&total| total <= MAX_DURATION_SECS)
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || total <= 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::seconds(total))
+}
+
+/// Formats [duration] like "1h30m", so that [parse_duration] reads it back
+pub fn format_duration(duration: ::chrono::Duration) -> String {
+    let units = [('w', 7 * 24 * 60 * 60), ('d', 24 * 60 * 60), ('h', 60 * 60), ('m', 60), ('s', 1)];
+
+    let mut rest = duration.num_seconds();
+    let mut formatted = String::new();
+    for &(unit, seconds) in units.iter() {
+        if rest >= seconds {
+            formatted.push_str(&format!("{}{}", rest / seconds, unit));
+            rest %= seconds;
+        }
+    }
+    formatted
+}
+
+/// (De)serializes durations as strings, see [parse_duration] and [format_duration]
+pub mod duration_string {
+    use super::{format_duration, parse_duration};
+    use chrono::Duration;
+    use serde::de::Error as DeError;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_duration(*duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        parse_duration(&string).map_err(D::Error::custom)
+    }
+
+    /// Like [duration_string] for optional durations
+    pub mod option {
+        use chrono::Duration;
+        use serde::de::Error as DeError;
+        use serde::{Deserialize, Deserializer, Serializer};
+        use utils::parse_duration;
+
+        pub fn serialize<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match *duration {
+                Some(ref duration) => super::serialize(duration, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            match Option::<String>::deserialize(deserializer)? {
+                Some(string) => parse_duration(&string).map(Some).map_err(D::Error::custom),
+                None => Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_duration, parse_duration, write_atomically};
+    use chrono::Duration;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all};
+    use std::io::Write;
+    use std::process;
+
+    #[test]
+    fn parses_combined_units() {
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_duration(" 2w "), Ok(Duration::weeks(2)));
+    }
+
+    #[test]
+    fn formatted_durations_are_parsed_back() {
+        assert_eq!(format_duration(Duration::minutes(90)), "1h30m");
+        assert_eq!(format_duration(Duration::seconds(45)), "45s");
+
+        for string in ["1h30m", "2w", "1w1d1h1m1s", "100s"].iter() {
+            let duration = parse_duration(string).unwrap();
+            assert_eq!(parse_duration(&format_duration(duration)), Ok(duration));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("5").is_err());
+        assert!(parse_duration("5y").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_which_are_too_long() {
+        assert!(parse_duration("99999999999999d").is_err());
+        assert!(parse_duration("9999999999d").is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+        assert!(parse_duration("5200w").is_ok());
+    }
+
+    #[test]
+    fn failed_writes_keep_the_previous_file() {
+        let dir = temp_dir().join(format!("heaven-on-earth-utils-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let path = dir.join("file.txt");
+
+        write_atomically(&path, |file| file.write_all(b"first").map_err(|e| e.to_string()))
+            .unwrap();
+        let failed = write_atomically(&path, |file| {
+            file.write_all(b"second").unwrap();
+            Err("failed".to_owned())
+        });
+
+        assert!(failed.is_err());
+        assert_eq!(read_to_string(&path).unwrap(), "first");
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+
+        remove_dir_all(&dir).ok();
+    }
+}
@@ -1,14 +1,46 @@
+use self::DownloadError::*;
 use super::immeta::{load_from_buf, GenericMetadata::*};
 use super::reddit;
-use super::reqwest;
 use super::serde_json;
 use super::serde_json::Value as JsonVal;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use colors::Colors;
 use configuration::Configuration;
-use rand::{thread_rng, Rng};
+use derived::{self, Animated};
+use http::Http;
+use image::codecs::webp::WebPDecoder;
+use image::io::Reader as ImageReader;
+use image::{self, ImageFormat};
+use index::{self, Entry, Index};
+use pattern::Pattern;
+use rand::Rng;
+use reqwest::blocking::Response;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE,
+    LAST_MODIFIED, RANGE,
+};
+use reqwest::StatusCode;
+use selection::{self, weighted_shuffle};
+use std::ffi::OsStr;
 use std::fs::read_dir;
-use std::fs::{canonicalize, create_dir_all, File};
-use std::io::{Read, Write};
+use std::fs::{canonicalize, create_dir_all, read, read_to_string, remove_file, write};
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+use utils::write_atomically;
+
+/// The extension appended to files which are still being written
+pub const PARTIAL_EXTENSION: &str = "part";
+
+/// The extension of the files storing the 'ETag' or 'Last-Modified' date of partial downloads
+pub const VALIDATOR_EXTENSION: &str = "validator";
+
+/// The amount of bytes after which the dimensions of a partial download are no longer checked
+const SNIFF_LIMIT: usize = 512 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct Wallpaper {
@@ -19,67 +51,186 @@ pub struct Wallpaper {
     pub file: Option<PathBuf>,
     /// the dimensions (x, y) of this wallpaper or [None] if it hasn't been downloaded yet
     pub dimensions: Option<(u32, u32)>,
+    /// the file derived from [file] which is set instead of it, e.g. if its format isn't supported
+    pub derived: Option<PathBuf>,
+    /// 'true' if this wallpaper is an animation
+    pub animated: bool,
+    /// the EXIF orientation of this wallpaper, from 1 (upright) to 8
+    pub orientation: u32,
+    pub nsfw: bool,
+    pub spoiler: bool,
+    /// 'true' if the post has been removed by a moderator, Reddit or its author
+    pub removed: bool,
+    pub video: bool,
+    pub stickied: bool,
+    pub score: i64,
+    /// the share of upvotes among all votes, from 0 to 1
+    pub upvote_ratio: f32,
+    pub comments: u32,
+    /// the point in time at which this wallpaper was posted
+    pub created: Option<DateTime<Utc>>,
+    pub flair: Option<String>,
+    pub author: Option<String>,
+    /// the host of [url]
+    pub domain: Option<String>,
+    /// the perceptual hash of this wallpaper or [None] if it hasn't been saved yet
+    pub hash: Option<u64>,
+    /// the colors of this wallpaper or [None] if they haven't been analyzed
+    pub colors: Option<Colors>,
 }
 
 impl Wallpaper {
     /// Tries to find a single wallpaper on Reddit
     pub fn find(config: &Configuration) -> Option<Self> {
-        // 'true' if the wallpaper matches the query set in the configuration, else 'false'
-        fn wallpaper_ok(wall: &Wallpaper, cfg: &Configuration) -> bool {
-            let ratio = match wall.ratio() {
-                Some(ratio) => ratio,
-                None => return false,
-            };
-            let size = match wall.megapixel() {
-                Some(size) => size,
-                None => return false,
-            };
+        let http = match Http::new(config) {
+            Ok(http) => http,
+            Err(e) => {
+                error!("{}", e);
+                return None;
+            }
+        };
 
-            let wide_enough = cfg.min_ratio.map(|min| ratio >= min).unwrap_or(true);
-            let tall_enough = cfg.max_ratio.map(|max| ratio <= max).unwrap_or(true);
-            let is_current = match ::wallpaper_lib::get() {
-                Ok(path) => path.contains(&wall.construct_filename()),
-                Err(_) => true,
-            };
-            let big_enough = cfg.min_res.map(|mp| size >= mp).unwrap_or(true);
+        let index = Mutex::new(Index::load(&config.output_dir));
+        let mut rng = selection::rng(config.seed);
+        let candidates = Wallpaper::search_on_reddit(config, &http, &mut rng);
 
-            wide_enough && tall_enough && big_enough && !is_current
-        }
+        let found = if config.parallel_downloads > 1 {
+            Wallpaper::find_parallel(candidates, &http, &index, config)
+        } else {
+            candidates
+                .into_iter()
+                .take_while(|_| !::shutdown::requested())
+                .find_map(|mut wallpaper| {
+                    let ok = wallpaper.fetch(&http, &index, config)
+                        && accept(&mut wallpaper, &index, config);
+                    if ok {
+                        Some(wallpaper)
+                    } else {
+                        None
+                    }
+                })
+        };
 
-        // the directory for saving downloaded images
-        let out = &config.output_dir;
-
-        for wallpaper in Wallpaper::search_on_reddit(config).iter_mut() {
-            // download every wallpaper
-            match wallpaper.download() {
-                Ok(data) => match wallpaper.save(out, &data) {
-                    Ok(_) => if wallpaper_ok(wallpaper, config) {
-                        return Some(wallpaper.clone());
-                    },
-                    Err(e) => warn!("Downloaded wallpaper could not be saved: {}", e),
-                },
-                Err(e) => warn!("Wallpaper could not be downloaded: {}", e),
+        let dir = Path::new(&config.output_dir);
+        if dir.is_dir() {
+            // only the images are merged, so that wallpapers banned or set
+            // and images removed by others in the meantime aren't lost
+            let searched = index.into_inner().unwrap();
+            let merged = Index::update(dir, |index| {
+                for (name, entry) in searched.images {
+                    if dir.join(&name).is_file() {
+                        index.images.insert(name, entry);
+                    }
+                }
+            });
+            if let Err(error) = merged {
+                warn!("{}", error);
             }
         }
 
-        // we have not found a wallpaper
-        None
+        found
     }
 
-    /// Search for wallpapers on Reddit
-    pub fn search_on_reddit(config: &Configuration) -> Vec<Self> {
-        let url = reddit::create_url(config);
+    /// Downloads [candidates] using [Configuration::parallel_downloads] threads and returns
+    /// the first one in the order of [candidates] which is ok, just like [find] would
+    fn find_parallel(
+        candidates: Vec<Wallpaper>,
+        http: &Http,
+        index: &Mutex<Index>,
+        config: &Configuration,
+    ) -> Option<Self> {
+        let workers = (config.parallel_downloads as usize).min(candidates.len());
+        let next_candidate = AtomicUsize::new(0);
+        let found = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let (sender, results) = channel();
+            for _ in 0..workers {
+                let sender = sender.clone();
+                let (candidates, next_candidate, found) = (&candidates, &next_candidate, &found);
+                scope.spawn(move || loop {
+                    let position = next_candidate.fetch_add(1, Ordering::SeqCst);
+                    if position >= candidates.len()
+                        || found.load(Ordering::SeqCst)
+                        || ::shutdown::requested()
+                    {
+                        break;
+                    }
+
+                    let mut wallpaper = candidates[position].clone();
+                    let fetched = wallpaper.fetch(http, index, config);
+                    if sender.send((position, wallpaper, fetched)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+
+            // the fetched candidates, which are checked in order as soon as their predecessors are
+            let mut fetched: Vec<Option<Option<Wallpaper>>> = vec![None; candidates.len()];
+            let mut next_checked = 0;
+            for (position, wallpaper, ok) in results.iter() {
+                fetched[position] = Some(if ok { Some(wallpaper) } else { None });
+
+                while let Some(Some(result)) = fetched.get_mut(next_checked).map(Option::take) {
+                    next_checked += 1;
+                    if let Some(mut wallpaper) = result {
+                        if accept(&mut wallpaper, index, config) {
+                            found.store(true, Ordering::SeqCst);
+                            return Some(wallpaper);
+                        }
+                    }
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Downloads and saves this wallpaper and adds it to [index], returns 'true' if successful
+    fn fetch(&mut self, http: &Http, index: &Mutex<Index>, config: &Configuration) -> bool {
+        match self.download(http, config) {
+            Ok(data) => match self.save(&config.output_dir, &data) {
+                Ok(_) => {
+                    self.update_hash(index);
+                    if config.filters_colors() {
+                        self.update_colors(index);
+                    }
+                    true
+                }
+                Err(e) => {
+                    warn!("Downloaded wallpaper could not be saved: {}", e);
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("Wallpaper could not be downloaded: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Search for wallpapers on Reddit, using [rng] for choosing the subreddit and the order
+    pub fn search_on_reddit<R: Rng>(config: &Configuration, http: &Http, rng: &mut R) -> Vec<Self> {
+        let url = reddit::create_url(config, rng);
         let mut body = String::new();
-        match reqwest::get(&url) {
-            Ok(r) => r,
+        let read = http.get(&url).and_then(|mut response| {
+            response
+                .read_to_string(&mut body)
+                .map_err(|e| format!("could not read response: {}", e))
+        });
+        if let Err(e) = read {
+            error!("Could not reach reddit: {}", e);
+            return Vec::new();
+        }
+
+        let json = match serde_json::from_str::<JsonVal>(&body[..]) {
+            Ok(json) => json,
             Err(e) => {
-                error!("Could not reach reddit: {}", e);
+                error!("Could not parse response of reddit: {}", e);
                 return Vec::new();
             }
-        }.read_to_string(&mut body)
-        .unwrap();
-
-        let json = serde_json::from_str::<JsonVal>(&body[..]).unwrap();
+        };
 
         let mut wallpapers = json
             .get("data")
@@ -91,11 +242,22 @@ impl Wallpaper {
                     .iter()
                     .filter_map(|child| child.get("data"))
                     .filter_map(|post| Wallpaper::from_json(post).ok())
+                    .filter(|wallpaper| wallpaper.post_ok(config))
                     .collect()
             });
 
-        if config.random {
-            thread_rng().shuffle(&mut wallpapers);
+        match config.weighted_by {
+            Some(weighting) => {
+                let temperature = config.temperature;
+                weighted_shuffle(&mut wallpapers, weighting, temperature, rng)
+            }
+            None if config.random => rng.shuffle(&mut wallpapers),
+            None => {}
+        }
+        if !config.prefer_titles.is_empty() {
+            // posts with a preferred title first, keeping the order otherwise
+            let prefer = &config.prefer_titles;
+            wallpapers.sort_by_key(|wallpaper| !wallpaper.matches_any(prefer, config));
         }
 
         for wallpaper in wallpapers.iter_mut() {
@@ -108,6 +270,85 @@ impl Wallpaper {
         wallpapers
     }
 
+    /// 'true' if the post of this wallpaper may be used according to [config],
+    /// which is known before downloading it
+    pub fn post_ok(&self, config: &Configuration) -> bool {
+        let rejection = if self.removed {
+            "it has been removed"
+        } else if self.video {
+            "it is a video"
+        } else if self.nsfw && !config.allow_nsfw {
+            "it is NSFW"
+        } else if self.spoiler && !config.allow_spoilers {
+            "it is a spoiler"
+        } else if self.stickied && !config.allow_stickied {
+            "it is stickied"
+        } else if config.min_score.map(|min| self.score < min).unwrap_or(false) {
+            "its score is too low"
+        } else if config.min_upvote_ratio.map(|min| self.upvote_ratio < min).unwrap_or(false) {
+            "its upvote ratio is too low"
+        } else if config.min_comments.map(|min| self.comments < min).unwrap_or(false) {
+            "it has too few comments"
+        } else if exceeds(&self.age(), &config.max_age, |age, max| age > max) {
+            "it is too old"
+        } else if exceeds(&self.age(), &config.min_age, |age, min| age < min) {
+            "it is too young"
+        } else if self.matches_any(&config.exclude_titles, config) {
+            "its title is excluded"
+        } else if !config.include_titles.is_empty()
+            && !self.matches_any(&config.include_titles, config)
+        {
+            "its title is not included"
+        } else if self.author_in(&config.blocked_authors) {
+            "its author is blocked"
+        } else if !config.allowed_authors.is_empty() && !self.author_in(&config.allowed_authors) {
+            "its author is not allowed"
+        } else if self.domain_in(&config.blocked_domains) {
+            "its domain is blocked"
+        } else if !config.allowed_domains.is_empty() && !self.domain_in(&config.allowed_domains) {
+            "its domain is not allowed"
+        } else {
+            return true;
+        };
+
+        debug!("Skipping '{}' because {}", self.title, rejection);
+        false
+    }
+
+    /// 'true' if one of [patterns] is found in the title or, if
+    /// [Configuration::match_flair] is set, the flair of this wallpaper
+    pub fn matches_any(&self, patterns: &[Pattern], config: &Configuration) -> bool {
+        let flair = self.flair.as_ref().filter(|_| config.match_flair);
+        patterns.iter().any(|pattern| {
+            pattern.matches(&self.title) || flair.map(|f| pattern.matches(f)).unwrap_or(false)
+        })
+    }
+
+    /// 'true' if the author of this wallpaper is one of [authors], ignoring the case
+    fn author_in(&self, authors: &[String]) -> bool {
+        match self.author {
+            Some(ref author) => authors.iter().any(|a| a.eq_ignore_ascii_case(author)),
+            None => false,
+        }
+    }
+
+    /// 'true' if this wallpaper is hosted on one of [domains] or their subdomains
+    fn domain_in(&self, domains: &[String]) -> bool {
+        let domain = match self.domain {
+            Some(ref domain) => domain.to_lowercase(),
+            None => return false,
+        };
+        domains.iter().map(|d| d.to_lowercase()).any(|d| {
+            domain == d || domain.ends_with(&format!(".{}", d))
+        })
+    }
+
+    /// The time which has passed since this wallpaper was posted
+    pub fn age(&self) -> Option<Duration> {
+        self.created
+            .map(|created| Utc::now().signed_duration_since(created))
+    }
+
     /// Calculates the width/height ratio of this image
     pub fn ratio(&self) -> Option<f32> {
         self.dimensions
@@ -119,9 +360,69 @@ impl Wallpaper {
             .map(|(width, height)| width as f32 * height as f32 / 1_000_000.0)
     }
 
+    /// 'true' if the [dimensions] are known and match the ratio and resolution set in [config]
+    pub fn dimensions_ok(&self, config: &Configuration) -> bool {
+        let (ratio, size) = match (self.ratio(), self.megapixel()) {
+            (Some(ratio), Some(size)) => (ratio, size),
+            _ => return false,
+        };
+
+        let wide_enough = config.min_ratio.map(|min| ratio >= min).unwrap_or(true);
+        let tall_enough = config.max_ratio.map(|max| ratio <= max).unwrap_or(true);
+        let big_enough = config.min_res.map(|mp| size >= mp).unwrap_or(true);
+
+        wide_enough && tall_enough && big_enough
+    }
+
+    /// 'true' if the [format] is unknown or can be decoded, or if this wallpaper
+    /// can be set as it is because [config] requires neither converting it nor its colors
+    pub fn format_ok(&self, config: &Configuration) -> bool {
+        let format = match self.format {
+            Some(ref format) => format,
+            None => return true,
+        };
+
+        derived::can_decode(format)
+            || (config.display_formats.contains(format)
+                && self.orientation <= 1
+                && !config.filters_colors())
+    }
+
+    /// 'true' if no colors are required by [config] or
+    /// the [colors] are known and match the brightness, contrast and hue set in it
+    pub fn colors_ok(&self, config: &Configuration) -> bool {
+        if !config.filters_colors() {
+            return true;
+        }
+        let colors = match self.colors {
+            Some(ref colors) => colors,
+            None => return false,
+        };
+
+        let bright_enough = config
+            .min_brightness
+            .map(|min| colors.brightness >= min)
+            .unwrap_or(true);
+        let dark_enough = config
+            .max_brightness
+            .map(|max| colors.brightness <= max)
+            .unwrap_or(true);
+        let calm_enough = config
+            .max_contrast
+            .map(|max| colors.contrast <= max)
+            .unwrap_or(true);
+        let hue_matches = config
+            .prefer_hue
+            .as_ref()
+            .map(|hue| colors.has_hue(hue))
+            .unwrap_or(true);
+
+        bright_enough && dark_enough && calm_enough && hue_matches
+    }
+
     /// Sets this wallpaper as a background image
     pub fn set(&self) -> Result<(), String> {
-        let file: Option<PathBuf> = self.file.clone();
+        let file: Option<PathBuf> = self.derived.clone().or_else(|| self.file.clone());
 
         let file_path = file
             .ok_or_else(|| "wallpaper is not saved yet!".to_string())
@@ -136,19 +437,213 @@ impl Wallpaper {
             .map_err(|error| format!("could not set wallpaper {}: {}", file_path, error))?
     }
 
-    /// Downloads this wallpaper from its [url] and computes/sets its [format] and [dimensions]
-    pub fn download(&mut self) -> Result<Vec<u8>, String> {
-        let mut bytes = Vec::new();
-        reqwest::get(&self.url)
-            .map_err(|error| format!("request failed: {}", error))?
-            .read_to_end(&mut bytes)
-            .map_err(|error| format!("could not read image into buffer: {}", error))?;
+    /// Downloads this wallpaper from its [url] and computes/sets its [format] and [dimensions].
+    /// Aborts if a shutdown is requested, the response is no image, exceeds
+    /// [Configuration::max_size] or its dimensions don't match [config].
+    /// Interrupted downloads are kept in [Configuration::output_dir] and resumed if possible.
+    pub fn download(&mut self, http: &Http, config: &Configuration) -> Result<Vec<u8>, String> {
+        let (partial_path, validator_path) = self.resume_paths(&config.output_dir);
+        let discard = || {
+            remove_file(&partial_path).ok();
+            remove_file(&validator_path).ok();
+        };
+
+        let result = self.receive(http, config, &partial_path, &validator_path);
+        match result {
+            // interrupted downloads can be resumed later
+            Err(Interrupted(error)) => Err(error),
+            Err(Rejected(error)) => {
+                discard();
+                Err(error)
+            }
+            Ok(bytes) => {
+                discard();
+                self.update_with_image_data(&bytes[..])?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Streams this wallpaper into [partial_path], continuing where a previous download stopped
+    /// if [validator_path] identifies the same version of the image
+    fn receive(
+        &mut self,
+        http: &Http,
+        config: &Configuration,
+        partial_path: &Path,
+        validator_path: &Path,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let max_size = (config.max_size * 1_000_000.0) as u64;
+
+        let resume = read_validator(validator_path)
+            .and_then(|validator| read(partial_path).ok().map(|bytes| (bytes, validator)))
+            .filter(|(bytes, _)| !bytes.is_empty());
+        let mut headers = HeaderMap::new();
+        if let Some((ref bytes, ref validator)) = resume {
+            let range = format!("bytes={}-", bytes.len());
+            headers.insert(RANGE, HeaderValue::from_str(&range).unwrap());
+            headers.insert(IF_RANGE, validator.clone());
+        }
+
+        let mut response = match http.get_with_headers(&self.url, headers) {
+            Ok(response) => response,
+            Err(error) if resume.is_some() => {
+                warn!("Could not resume download, starting over: {}", error);
+                http.get(&self.url).map_err(Interrupted)?
+            }
+            Err(error) => return Err(Interrupted(error)),
+        };
+
+        if let Some(content_type) = header(&response, CONTENT_TYPE) {
+            if !content_type.to_lowercase().starts_with("image/") {
+                return Err(Rejected(format!("{} is no image", content_type)));
+            }
+        }
+
+        let mut bytes = match resume {
+            Some((bytes, _)) if resumes_at(&response, bytes.len() as u64) => {
+                info!("Resuming download at {} KB", bytes.len() / 1000);
+                bytes
+            }
+            _ => Vec::new(),
+        };
+
+        if let Some(length) = response.content_length() {
+            let total = bytes.len() as u64 + length;
+            if total > max_size {
+                let megabytes = total as f32 / 1_000_000.0;
+                return Err(Rejected(format!("image too large ({:.1} MB)", megabytes)));
+            }
+        }
+
+        if let Some(dir) = partial_path.parent() {
+            create_dir_all(dir).map_err(|e| Interrupted(format!("could not create path: {}", e)))?;
+        }
+        let mut partial = if bytes.is_empty() {
+            match validator(&response) {
+                Some(validator) => write(validator_path, validator).ok(),
+                None => remove_file(validator_path).ok(),
+            };
+            File::create(partial_path)
+        } else {
+            OpenOptions::new().append(true).open(partial_path)
+        }.map_err(|e| Interrupted(format!("could not create file: {}", e)))?;
+
+        let mut buffer = [0; 8 * 1024];
+        loop {
+            if ::shutdown::requested() {
+                return Err(Interrupted("download aborted".to_owned()));
+            }
+            let read = match response.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(error) => {
+                    let error = format!("could not read image into buffer: {}", error);
+                    return Err(Interrupted(error));
+                }
+            };
+            bytes.extend_from_slice(&buffer[..read]);
+            partial
+                .write_all(&buffer[..read])
+                .map_err(|e| Interrupted(format!("could not write to file: {}", e)))?;
+
+            if bytes.len() as u64 > max_size {
+                return Err(Rejected(format!("image larger than {} MB", config.max_size)));
+            }
 
-        self.update_with_image_data(&bytes[..])?;
+            // check the dimensions as soon as the header has been received
+            let sniffed = self.dimensions.is_none()
+                && bytes.len() <= SNIFF_LIMIT
+                && self.update_with_image_data(&bytes[..]).is_ok();
+            if sniffed && !self.dimensions_ok(config) {
+                let (width, height) = self.dimensions.unwrap();
+                return Err(Rejected(format!("dimensions {}x{} don't match", width, height)));
+            }
+            if sniffed && !self.format_ok(config) {
+                let format = self.format.clone().unwrap_or_default();
+                return Err(Rejected(format!("{} images can't be decoded", format)));
+            }
+        }
 
         Ok(bytes)
     }
 
+    /// Sets [hash] to the perceptual hash of the saved file of this wallpaper,
+    /// which is looked up in or else added to [index]
+    fn update_hash(&mut self, index: &Mutex<Index>) {
+        let file = match self.file {
+            Some(ref file) => file.clone(),
+            None => return,
+        };
+        let name = file.file_name().unwrap().to_string_lossy().into_owned();
+
+        let known = index.lock().unwrap().images.get(&name).cloned();
+        let hash = match known {
+            Some(ref entry) => entry.hash,
+            None => match index::hash_file(&file, self.orientation) {
+                Ok(hash) => hash,
+                Err(e) => {
+                    warn!("Could not compute the hash of '{}': {}", self.title, e);
+                    return;
+                }
+            },
+        };
+
+        let entry = Entry {
+            hash,
+            title: self.title.clone(),
+            url: Some(self.url.clone()),
+            colors: known.and_then(|entry| entry.colors),
+        };
+        index.lock().unwrap().images.insert(name, entry);
+        self.hash = Some(hash);
+    }
+
+    /// Sets [colors] to the colors of the saved file of this wallpaper,
+    /// which are looked up in or else added to its entry in [index]
+    fn update_colors(&mut self, index: &Mutex<Index>) {
+        let file = match self.file {
+            Some(ref file) => file.clone(),
+            None => return,
+        };
+        let name = file.file_name().unwrap().to_string_lossy().into_owned();
+
+        let known = index
+            .lock()
+            .unwrap()
+            .images
+            .get(&name)
+            .and_then(|entry| entry.colors.clone());
+        let colors = match known {
+            Some(colors) => colors,
+            None => match Colors::analyze(&file) {
+                Ok(colors) => colors,
+                Err(e) => {
+                    warn!("Could not analyze the colors of '{}': {}", self.title, e);
+                    return;
+                }
+            },
+        };
+
+        info!("Colors of '{}': {}", self.title, colors);
+        if let Some(entry) = index.lock().unwrap().images.get_mut(&name) {
+            entry.colors = Some(colors.clone());
+        }
+        self.colors = Some(colors);
+    }
+
+    /// The paths of the partial download of this wallpaper in [dir]
+    /// and of the file identifying its version on the server.
+    /// They depend on the [url], so posts with the same title don't share them.
+    fn resume_paths<P: AsRef<Path>>(&self, dir: P) -> (PathBuf, PathBuf) {
+        let name = format!("{}-{:016x}", self.base_filename(), url_hash(&self.url));
+        let dir = dir.as_ref();
+        (
+            dir.join(format!("{}.{}", name, PARTIAL_EXTENSION)),
+            dir.join(format!("{}.{}", name, VALIDATOR_EXTENSION)),
+        )
+    }
+
     /// Saves this wallpaper in [directory] and sets [file] to the path of the created file
     pub fn save<P: AsRef<Path>>(&mut self, dir: P, image_data: &[u8]) -> Result<(), String> {
         let dir = dir.as_ref();
@@ -163,10 +658,10 @@ impl Wallpaper {
             create_dir_all(dir).map_err(|e| format!("could not create path: {}", e))?;
         }
 
-        File::create(&path)
-            .map_err(|e| format!("could not create file: {}", e))?
-            .write(image_data)
-            .map_err(|e| format!("could not write to file: {}", e))?;
+        write_atomically(&path, |file| {
+            file.write_all(image_data)
+                .map_err(|e| format!("could not write to file: {}", e))
+        })?;
 
         self.file = Some(path);
 
@@ -190,6 +685,8 @@ impl Wallpaper {
             .filter_map(|path| path.ok())
             .map(|dir_entry| dir_entry.path())
             .filter(|path| path.is_file())
+            .filter(|path| path.extension() != Some(OsStr::new(PARTIAL_EXTENSION)))
+            .filter(|path| path.extension() != Some(OsStr::new(VALIDATOR_EXTENSION)))
             .filter(|file| {
                 file.file_name()
                     .unwrap()
@@ -214,21 +711,127 @@ impl Wallpaper {
     }
 
     fn update_with_image_data(&mut self, data: &[u8]) -> Result<(), String> {
-        let image = load_from_buf(data)
-            .map_err(|error| format!("Computing dimensions failed: {}", error))?;
-        let dim = image.dimensions();
-        self.dimensions = Some((dim.width, dim.height));
-        self.format = Some(
-            match image {
-                Jpeg(_) => "jpeg",
-                Png(_) => "png",
-                Gif(_) => "gif",
-                _ => return Err("Image format not supported".to_owned()),
-            }.to_owned(),
-        );
+        let mut animated = false;
+        let (format, dimensions) = match load_from_buf(data) {
+            Ok(image) => {
+                let dim = image.dimensions();
+                let format = match image {
+                    Jpeg(_) => "jpeg",
+                    Png(_) => "png",
+                    Gif(ref gif) => {
+                        animated = gif.is_animated();
+                        "gif"
+                    }
+                    Webp(_) => {
+                        animated = WebPDecoder::new(Cursor::new(data))
+                            .map(|webp| webp.has_animation())
+                            .unwrap_or(false);
+                        "webp"
+                    }
+                };
+                (format, (dim.width, dim.height))
+            }
+            Err(error) => match image::guess_format(data) {
+                Ok(ImageFormat::Avif) => ("avif", avif_dimensions(data)?),
+                Ok(format @ ImageFormat::Bmp)
+                | Ok(format @ ImageFormat::Tiff)
+                | Ok(format @ ImageFormat::WebP) => {
+                    let dimensions = ImageReader::with_format(Cursor::new(data), format)
+                        .into_dimensions()
+                        .map_err(|error| format!("Computing dimensions failed: {}", error))?;
+                    (format.extensions_str()[0], dimensions)
+                }
+                Ok(_) => return Err("Image format not supported".to_owned()),
+                Err(_) => return Err(format!("Computing dimensions failed: {}", error)),
+            },
+        };
+        let orientation = derived::orientation(data);
+        self.dimensions = Some(if derived::is_transposed(orientation) {
+            (dimensions.1, dimensions.0)
+        } else {
+            dimensions
+        });
+        self.format = Some(format.to_owned());
+        self.animated = animated;
+        self.orientation = orientation;
         Ok(())
     }
 
+    /// Converts this wallpaper if its format can't be set as a wallpaper or it isn't upright,
+    /// or replaces it by a still frame if it's animated, returns 'true' if it can be set afterwards
+    fn prepare(&mut self, config: &Configuration) -> bool {
+        let (file, format) = match (&self.file, &self.format) {
+            (Some(file), Some(format)) => (file.clone(), format.clone()),
+            _ => return false,
+        };
+
+        if self.animated {
+            if config.animated == Animated::Skip {
+                info!("Skipping animated image '{}'", self.title);
+                return false;
+            }
+            return match derived::still_frame(&file, &format) {
+                Ok(path) => {
+                    self.derived = Some(path);
+                    true
+                }
+                Err(e) => {
+                    warn!("Could not extract a still frame: {}", e);
+                    false
+                }
+            };
+        }
+
+        let displayable = config.display_formats.contains(&format);
+        if displayable && self.orientation <= 1 {
+            return true;
+        }
+
+        let target = match &*format {
+            "jpeg" | "png" if displayable => format.clone(),
+            _ => config.convert_to.clone(),
+        };
+        if displayable {
+            info!("Turning image upright...");
+        } else {
+            info!("Converting {} image to {}...", format, target);
+        }
+        match derived::convert(&file, &target, self.orientation) {
+            Ok(path) => {
+                self.derived = Some(path);
+                true
+            }
+            Err(e) => {
+                warn!("Wallpaper could not be converted: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Darkens this wallpaper if it is brighter than [Configuration::target_brightness].
+    /// The wallpaper is set as it is if that fails.
+    fn dim(&mut self, config: &Configuration) {
+        let (target, original) = match (config.target_brightness, &self.file) {
+            (Some(target), Some(file)) => (target, file.clone()),
+            _ => return,
+        };
+        if let Some(ref colors) = self.colors {
+            if colors.brightness <= target {
+                return;
+            }
+        }
+
+        let source = self.derived.clone().unwrap_or_else(|| original.clone());
+        match derived::dim(&original, &source, target, config.dimming, &config.convert_to) {
+            Ok(ref path) if *path == source => {}
+            Ok(path) => {
+                info!("Darkened wallpaper to a brightness of {}", target);
+                self.derived = Some(path);
+            }
+            Err(e) => warn!("Wallpaper could not be darkened: {}", e),
+        }
+    }
+
     /// The path where a wallpaper should be saved depending
     /// on its title, format and the given directory
     fn construct_path<P: AsRef<Path>>(&self, dir: P) -> Option<PathBuf> {
@@ -241,24 +844,28 @@ impl Wallpaper {
     /// The name under which a wallpaper should be stored
     /// on disk depending on its title and format
     fn construct_filename(&self) -> String {
+        let new_name = self.base_filename();
+
+        match &self.format {
+            Some(format) => format!("{}.{}", new_name, format),
+            None => new_name,
+        }
+    }
+
+    /// The name of this wallpaper on disk depending on its title only
+    fn base_filename(&self) -> String {
         static FORBIDDEN: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
 
-        let new_name: String = self
-            .title
+        self.title
             .trim()
             .chars()
             .flat_map(char::to_lowercase)
             .filter(|c| !FORBIDDEN.contains(c))
             .map(|c| if c == ' ' { '_' } else { c })
-            .collect();
-
-        match &self.format {
-            Some(format) => format!("{}.{}", new_name, format),
-            None => new_name,
-        }
+            .collect()
     }
 
-    fn from_json(json: &JsonVal) -> Result<Self, &'static str> {
+    pub fn from_json(json: &JsonVal) -> Result<Self, &'static str> {
         Ok(Wallpaper {
             title: json["title"]
                 .as_str()
@@ -271,6 +878,352 @@ impl Wallpaper {
             format: None,
             file: None,
             dimensions: None,
+            derived: None,
+            animated: false,
+            orientation: 1,
+            nsfw: json["over_18"].as_bool().unwrap_or(false),
+            spoiler: json["spoiler"].as_bool().unwrap_or(false),
+            removed: !json["removed_by_category"].is_null(),
+            video: json["is_video"].as_bool().unwrap_or(false),
+            stickied: json["stickied"].as_bool().unwrap_or(false),
+            score: json["score"].as_i64().unwrap_or(0),
+            upvote_ratio: json["upvote_ratio"].as_f64().unwrap_or(1.0) as f32,
+            comments: json["num_comments"].as_u64().unwrap_or(0) as u32,
+            created: json["created_utc"]
+                .as_f64()
+                .map(|created| Utc.timestamp(created as i64, 0)),
+            flair: json["link_flair_text"].as_str().map(str::to_owned),
+            author: json["author"].as_str().map(str::to_owned),
+            domain: json["domain"].as_str().map(str::to_owned),
+            hash: None,
+            colors: None,
         })
     }
 }
+
+/// The reason why a download failed
+enum DownloadError {
+    /// the download may be resumed later
+    Interrupted(String),
+    /// the image is not wanted
+    Rejected(String),
+}
+
+/// The 64-bit FNV-1a hash of [url], which unlike the hashers of the standard library
+/// stays the same across versions, so that partial downloads are found again
+fn url_hash(url: &str) -> u64 {
+    url.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The value of the header [name] of [response], if it is present and readable
+fn header(response: &Response, name: HeaderName) -> Option<&str> {
+    response.headers().get(name)?.to_str().ok()
+}
+
+/// The 'If-Range' header stored in [path] for resuming a download
+fn read_validator(path: &Path) -> Option<HeaderValue> {
+    HeaderValue::from_str(read_to_string(path).ok()?.trim()).ok()
+}
+
+/// The strong 'ETag' or else the 'Last-Modified' date of [response],
+/// if the server accepts range requests
+fn validator(response: &Response) -> Option<String> {
+    let accepts_ranges = header(response, ACCEPT_RANGES)
+        .map(|ranges| ranges.split(',').any(|unit| unit.trim() == "bytes"))
+        .unwrap_or(false);
+    if !accepts_ranges {
+        return None;
+    }
+
+    match header(response, ETAG) {
+        Some(tag) if tag.starts_with('"') => Some(tag.to_owned()),
+        _ => header(response, LAST_MODIFIED).map(str::to_owned),
+    }
+}
+
+/// 'true' if [response] contains the rest of an image starting at [offset]
+fn resumes_at(response: &Response, offset: u64) -> bool {
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return false;
+    }
+    // e.g. 'bytes 1000-1999/2000'
+    header(response, CONTENT_RANGE)
+        .and_then(|range| range.trim().strip_prefix("bytes "))
+        .and_then(|range| range.split('-').next())
+        .and_then(|first| first.trim().parse::<u64>().ok())
+        == Some(offset)
+}
+
+/// The dimensions of the AVIF image [data], read from the 'ispe' property of its primary item
+/// in 'meta' > 'iprp' > 'ipco', or from the largest one if there is no such association
+fn avif_dimensions(data: &[u8]) -> Result<(u32, u32), String> {
+    let missing = |kind: &str| format!("Computing dimensions failed: no '{}' box found", kind);
+
+    // 'meta' and 'ispe' are full boxes, which start with their version and flags
+    let meta = find_box(data, b"meta")
+        .and_then(|meta| meta.get(4..))
+        .ok_or_else(|| missing("meta"))?;
+    let properties = find_box(meta, b"iprp")
+        .and_then(|iprp| find_box(iprp, b"ipco"))
+        .map(iso_boxes)
+        .ok_or_else(|| missing("ipco"))?;
+
+    // properties are referred to by their position, starting at 1
+    let sizes: Vec<(usize, (u32, u32))> = properties
+        .iter()
+        .enumerate()
+        .filter(|(_, (kind, _))| kind == b"ispe")
+        .filter_map(|(at, (_, ispe))| Some((at + 1, (read_u32(ispe, 4)?, read_u32(ispe, 8)?))))
+        .collect();
+
+    let primary = avif_primary_properties(meta).unwrap_or_default();
+    sizes
+        .iter()
+        .find(|(index, _)| primary.contains(index))
+        .or_else(|| {
+            sizes
+                .iter()
+                .max_by_key(|(_, (width, height))| u64::from(*width) * u64::from(*height))
+        }).map(|&(_, size)| size)
+        .ok_or_else(|| missing("ispe"))
+}
+
+/// The positions of the properties in 'ipco' which the 'ipma' box of the AVIF [meta] box
+/// associates with the primary item named by 'pitm'
+fn avif_primary_properties(meta: &[u8]) -> Option<Vec<usize>> {
+    let pitm = find_box(meta, b"pitm")?;
+    let primary = match *pitm.first()? {
+        0 => u32::from(read_u16(pitm, 4)?),
+        _ => read_u32(pitm, 4)?,
+    };
+
+    let ipma = find_box(find_box(meta, b"iprp")?, b"ipma")?;
+    let version = *ipma.first()?;
+    let wide_indices = ipma.get(3)? & 1 == 1;
+    let mut at = 8;
+    for _ in 0..read_u32(ipma, 4)? {
+        let item = if version < 1 {
+            at += 2;
+            u32::from(read_u16(ipma, at - 2)?)
+        } else {
+            at += 4;
+            read_u32(ipma, at - 4)?
+        };
+        let count = *ipma.get(at)?;
+        at += 1;
+
+        // every index is preceded by a bit which tells whether the property is essential
+        let mut indices = Vec::new();
+        for _ in 0..count {
+            let index = if wide_indices {
+                at += 2;
+                read_u16(ipma, at - 2)? & 0x7fff
+            } else {
+                at += 1;
+                u16::from(*ipma.get(at - 1)? & 0x7f)
+            };
+            indices.push(usize::from(index));
+        }
+
+        if item == primary {
+            return Some(indices);
+        }
+    }
+    None
+}
+
+/// The boxes of the ISO base media file format in [data] as their types and contents,
+/// without an incomplete box at the end
+fn iso_boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut at = 0;
+    while let (Some(size), Some(kind)) = (read_u32(data, at), data.get(at + 4..at + 8)) {
+        let remaining = (data.len() - at) as u64;
+        let (header, size) = match size {
+            0 => (8, remaining),
+            1 => match read_u64(data, at + 8) {
+                Some(size) => (16, size),
+                None => break,
+            },
+            size => (8, u64::from(size)),
+        };
+        if size < header as u64 || size > remaining {
+            break;
+        }
+
+        let end = at + size as usize;
+        match data.get(at + header..end) {
+            Some(content) => boxes.push((kind, content)),
+            None => break,
+        }
+        at = end;
+    }
+    boxes
+}
+
+/// The content of the first box of the type [kind] in [data]
+fn find_box<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    iso_boxes(data)
+        .into_iter()
+        .find(|&(found, _)| found == kind)
+        .map(|(_, content)| content)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    let bytes = data.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    let bytes = data.get(at..at + 8)?;
+    let mut array = [0; 8];
+    array.copy_from_slice(bytes);
+    Some(u64::from_be_bytes(array))
+}
+
+/// 'true' if [age] and the duration [limit] are known and [compare] returns 'true' for them
+fn exceeds<F>(age: &Option<Duration>, limit: &Option<Duration>, compare: F) -> bool
+where
+    F: Fn(Duration, Duration) -> bool,
+{
+    match (age, limit) {
+        (Some(age), Some(limit)) => compare(*age, *limit),
+        _ => false,
+    }
+}
+
+/// 'true' if the fetched [wallpaper] matches the query, is no repost of a wallpaper
+/// which has been set recently or banned and can be set, see [Wallpaper::prepare].
+/// Accepted wallpapers are darkened if necessary, see [Wallpaper::dim].
+fn accept(wallpaper: &mut Wallpaper, index: &Mutex<Index>, config: &Configuration) -> bool {
+    if !wallpaper_ok(wallpaper, config) {
+        return false;
+    }
+
+    if let Some(hash) = wallpaper.hash {
+        let mut index = index.lock().unwrap();
+        // wallpapers may have been banned or set since the search started
+        let current = Index::load(&config.output_dir);
+        index.recent = current.recent;
+        index.banned = current.banned;
+        let rejection = index.rejection(hash, config.duplicate_threshold);
+        drop(index);
+        if let Some(rejection) = rejection {
+            info!("Skipping '{}' because {}", wallpaper.title, rejection);
+            return false;
+        }
+    }
+
+    if !wallpaper.prepare(config) {
+        return false;
+    }
+    wallpaper.dim(config);
+    true
+}
+
+/// 'true' if the wallpaper matches the query set in the configuration, else 'false'
+fn wallpaper_ok(wall: &Wallpaper, cfg: &Configuration) -> bool {
+    let is_current = match ::wallpaper_lib::get() {
+        Ok(path) => path.contains(&wall.construct_filename()),
+        Err(_) => true,
+    };
+
+    wall.dimensions_ok(cfg) && wall.format_ok(cfg) && wall.colors_ok(cfg) && !is_current
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{avif_dimensions, url_hash, Wallpaper};
+
+    /// A box of the ISO base media file format with the type [kind] and [content]
+    fn iso_box(kind: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut data = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(content);
+        data
+    }
+
+    /// A full box, whose content starts with its version and flags
+    fn full_box(kind: &[u8], content: &[u8]) -> Vec<u8> {
+        iso_box(kind, &[&[0, 0, 0, 0], content].concat())
+    }
+
+    fn ispe(width: u32, height: u32) -> Vec<u8> {
+        full_box(b"ispe", &[width.to_be_bytes(), height.to_be_bytes()].concat())
+    }
+
+    /// An AVIF image whose primary item 1 has the second of two 'ispe' properties,
+    /// followed by image data which happens to contain the bytes 'ispe'
+    fn avif() -> Vec<u8> {
+        let ipco = iso_box(b"ipco", &[ispe(8000, 8000), ispe(1920, 1080)].concat());
+        // item 2 has the first property, item 1 the second one, both marked as essential
+        let ipma = full_box(b"ipma", &[0, 0, 0, 2, 0, 2, 1, 0x81, 0, 1, 1, 0x82]);
+        let meta = full_box(
+            b"meta",
+            &[full_box(b"pitm", &[0, 1]), iso_box(b"iprp", &[ipco, ipma].concat())].concat(),
+        );
+        let mdat = iso_box(b"mdat", &[b"ispe".to_vec(), ispe(9999, 9999)].concat());
+        [iso_box(b"ftyp", b"avifmif1"), meta, mdat].concat()
+    }
+
+    #[test]
+    fn posts_with_the_same_title_are_resumed_separately() {
+        let post = |url: &str| {
+            Wallpaper::from_json(&::serde_json::json!({ "title": "Sunset", "url": url })).unwrap()
+        };
+        let first = post("https://i.example.com/a.jpg").resume_paths("out");
+        let second = post("https://i.example.com/b.jpg").resume_paths("out");
+
+        assert_ne!(first.0, second.0);
+        assert_ne!(first.1, second.1);
+        assert_eq!(first, post("https://i.example.com/a.jpg").resume_paths("out"));
+    }
+
+    #[test]
+    fn url_hashes_are_stable() {
+        assert_eq!(url_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(url_hash("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn avif_dimensions_are_those_of_the_primary_item() {
+        assert_eq!(avif_dimensions(&avif()), Ok((1920, 1080)));
+    }
+
+    #[test]
+    fn avif_dimensions_fall_back_to_the_largest_property() {
+        let ipco = iso_box(b"ipco", &[ispe(512, 512), ispe(4000, 3000)].concat());
+        let meta = full_box(b"meta", &iso_box(b"iprp", &ipco));
+        let data = [iso_box(b"ftyp", b"avifmif1"), meta].concat();
+
+        assert_eq!(avif_dimensions(&data), Ok((4000, 3000)));
+    }
+
+    #[test]
+    fn avif_dimensions_need_the_whole_meta_box() {
+        let data = avif();
+        assert!(avif_dimensions(&data[..40]).is_err());
+        assert!(avif_dimensions(b"ispe").is_err());
+    }
+
+    #[test]
+    fn avif_dimensions_survive_a_truncated_ipma_box() {
+        // item 1 claims three associations, but only one follows
+        let ipco = iso_box(b"ipco", &ispe(1920, 1080));
+        let ipma = full_box(b"ipma", &[0, 0, 0, 1, 0, 1, 3, 0x81]);
+        let meta = full_box(
+            b"meta",
+            &[full_box(b"pitm", &[0, 1]), iso_box(b"iprp", &[ipco, ipma].concat())].concat(),
+        );
+        let data = [iso_box(b"ftyp", b"avifmif1"), meta].concat();
+
+        assert_eq!(avif_dimensions(&data), Ok((1920, 1080)));
+    }
+}